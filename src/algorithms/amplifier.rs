@@ -1,5 +1,113 @@
-use crate::intcode::{Error, Machine};
-use std::iter;
+use crate::intcode::network::{PipeReport, Topology, UnknownNode, Until};
+use crate::intcode::{Error, Machine, Status};
+use std::fmt;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NodeError {
+    pub node: usize,
+    pub error: Error,
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "node {}: {}", self.node, self.error)
+    }
+}
+
+impl std::error::Error for NodeError {}
+
+#[derive(Debug, Clone)]
+struct Node {
+    machine: Machine,
+    targets: Vec<usize>,
+    watched: bool,
+}
+
+// Wires machines into an arbitrary directed graph like `PipeNetwork`, but runs
+// them on the calling thread, each until it blocks on input, in the order they
+// were added. Values reaching a node from several sources therefore always
+// arrive in the same order. Every output is sent to every target.
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    nodes: Vec<Node>,
+}
+
+impl Topology for Pipeline {
+    fn add_node(&mut self, mut machine: Machine, prelude: Vec<i64>) -> usize {
+        prelude.into_iter().for_each(|val| machine.push_input(val));
+        self.nodes.push(Node {
+            machine,
+            targets: Vec::new(),
+            watched: false,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn pipe(&mut self, from: usize, to: usize) -> Result<&mut Self, UnknownNode> {
+        if to >= self.nodes.len() {
+            return Err(UnknownNode(to));
+        }
+        let node = self.nodes.get_mut(from).ok_or(UnknownNode(from))?;
+        node.targets.push(to);
+        Ok(self)
+    }
+
+    fn watch(&mut self, node: usize) -> Result<&mut Self, UnknownNode> {
+        self.nodes.get_mut(node).ok_or(UnknownNode(node))?.watched = true;
+        Ok(self)
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Stops once every node has halted or is waiting for input nobody can
+    // provide any more.
+    pub fn run(mut self, until: Until) -> Result<PipeReport, NodeError> {
+        let mut outputs = vec![Vec::new(); self.nodes.len()];
+        let mut halted = vec![false; self.nodes.len()];
+        let mut progress = true;
+        while progress {
+            progress = false;
+            for idx in 0..self.nodes.len() {
+                while !halted[idx] {
+                    let status = self.nodes[idx]
+                        .machine
+                        .resume()
+                        .map_err(|error| NodeError { node: idx, error })?;
+                    let val = match status {
+                        Status::Output(val) => val,
+                        Status::Halted => {
+                            halted[idx] = true;
+                            progress = true;
+                            break;
+                        }
+                        Status::NeedsInput => break,
+                    };
+                    progress = true;
+                    for target in 0..self.nodes[idx].targets.len() {
+                        let to = self.nodes[idx].targets[target];
+                        self.nodes[to].machine.push_input(val);
+                    }
+                    if self.nodes[idx].watched {
+                        outputs[idx].push(val);
+                        match until {
+                            Until::Outputs { node, count }
+                                if node == idx && outputs[idx].len() >= count =>
+                            {
+                                return Ok(PipeReport { outputs, halted });
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        Ok(PipeReport { outputs, halted })
+    }
+}
 
 pub fn score_setting<I>(machine: &Machine, setting: I) -> Result<Option<i64>, Error>
 where
@@ -10,34 +118,44 @@ where
             machine
                 .clone()
                 .run_to_end([s, input].iter().copied())
-                .map(|v| v.get(0).copied())
+                .map(|v| v.first().copied())
         })
     })
 }
 
 pub fn score_setting_feedback(machine: &Machine, setting: &[i64]) -> Result<Option<i64>, Error> {
-    let mut machines = vec![machine.clone(); setting.len()];
-    let mut feedback_input = vec![0];
-    for (&s, m) in setting.iter().zip(machines.iter_mut()) {
-        m.run_with(iter::once(s), &mut feedback_input)?;
-    }
-    let mut done = false;
-    while !done {
-        for m in &mut machines {
-            let mut out = Vec::new();
-            if m.run_with(feedback_input, &mut out)? == 0 {
-                return Ok(None);
+    if setting.is_empty() {
+        return Ok(None);
+    }
+    let mut machines: Vec<_> = setting
+        .iter()
+        .map(|&s| {
+            let mut m = machine.clone();
+            m.push_input(s);
+            m
+        })
+        .collect();
+    let mut signal = 0;
+    let mut last_output = None;
+    loop {
+        for (idx, m) in machines.iter_mut().enumerate() {
+            signal = match m.resume_with(signal)? {
+                Status::Output(val) => val,
+                Status::Halted => return Ok(last_output),
+                Status::NeedsInput => return Ok(None),
+            };
+            if idx == setting.len() - 1 {
+                last_output = Some(signal);
             }
-            done = m.done();
-            feedback_input = out;
         }
     }
-    Ok(Some(feedback_input[0]))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::intcode::asm::assemble;
+    use crate::intcode::ErrorKind;
 
     fn test_score_setting(machine: &[i64], setting: &[i64], expected: i64) {
         assert_eq!(
@@ -100,4 +218,99 @@ mod tests {
             18216,
         );
     }
+
+    #[test]
+    fn test_score_setting_needs_more_input() {
+        let machine = Machine::new(vec![3, 0, 3, 1, 3, 2, 4, 0, 99]);
+        assert_eq!(
+            score_setting(&machine, vec![1, 2]).unwrap_err().kind,
+            ErrorKind::Eof
+        );
+    }
+
+    const ECHO: &str = "
+        loop:   in [x]
+                out [x]
+                jt #1, #loop
+        x:      .data 0
+    ";
+
+    // Reads two values and outputs ten times the first plus the second.
+    const COMBINE: &str = "
+                in [a]
+                in [b]
+                mul [a], #10, [a]
+                add [a], [b], [a]
+                out [a]
+                end
+        a:      .data 0
+        b:      .data 0
+    ";
+
+    #[test]
+    fn pipeline_fan_out_and_in() {
+        let echo = Machine::new(assemble(ECHO).unwrap());
+        let combine = Machine::new(assemble(COMBINE).unwrap());
+        let mut pipeline = Pipeline::new();
+        let source = pipeline.add_node(echo.clone(), vec![7]);
+        let left = pipeline.add_node(echo.clone(), vec![1]);
+        let right = pipeline.add_node(echo, vec![]);
+        let sum = pipeline.add_node(combine, vec![]);
+        for (from, to) in [(source, left), (source, right), (left, sum), (right, sum)] {
+            pipeline.pipe(from, to).unwrap();
+        }
+        pipeline.watch(sum).unwrap().watch(right).unwrap();
+        let report = pipeline.run(Until::AllHalted).unwrap();
+        // `left` sends its prelude first, so `sum` sees 1 then 7.
+        assert_eq!(report.outputs[sum], &[17]);
+        assert_eq!(report.outputs[right], &[7]);
+        assert!(report.outputs[left].is_empty());
+        assert_eq!(report.halted, &[false, false, false, true]);
+    }
+
+    #[test]
+    fn pipeline_loops() {
+        // Two rings through a shared adder: every value it sends out comes
+        // back once from each ring.
+        let adder = Machine::new(
+            assemble("loop: in [x]\nadd [x], #1, [x]\nout [x]\njt #1, #loop\nx: .data 0").unwrap(),
+        );
+        let echo = Machine::new(assemble(ECHO).unwrap());
+        let mut pipeline = Pipeline::new();
+        let hub = pipeline.add_node(adder, vec![0, 100]);
+        let a = pipeline.add_node(echo.clone(), vec![]);
+        let b = pipeline.add_node(echo, vec![]);
+        for (from, to) in [(hub, a), (a, hub), (hub, b), (b, hub)] {
+            pipeline.pipe(from, to).unwrap();
+        }
+        pipeline.watch(hub).unwrap();
+        let report = pipeline
+            .run(Until::Outputs {
+                node: hub,
+                count: 6,
+            })
+            .unwrap();
+        assert_eq!(report.outputs[hub], &[1, 101, 2, 102, 2, 102]);
+        assert_eq!(report.halted, &[false; 3]);
+
+        let machine = Machine::new(vec![3, 0, 4, 0, 99]);
+        let report = Pipeline::chain(&machine, &[1, 2], true)
+            .run(Until::AllHalted)
+            .unwrap();
+        assert_eq!(report.outputs[1], &[2]);
+        assert_eq!(report.halted, &[true, true]);
+    }
+
+    #[test]
+    fn pipeline_reports_failing_node() {
+        let echo = Machine::new(assemble(ECHO).unwrap());
+        let mut pipeline = Pipeline::new();
+        let source = pipeline.add_node(echo, vec![5]);
+        let bad = pipeline.add_node(Machine::new(vec![3, 0, 42]), vec![]);
+        pipeline.pipe(source, bad).unwrap();
+        let err = pipeline.run(Until::AllHalted).unwrap_err();
+        assert_eq!(err.node, bad);
+        assert_eq!(err.error.kind, ErrorKind::UnknownOpcode { opcode: 42 });
+        assert!(err.to_string().starts_with("node 1: unknown opcode 42"));
+    }
 }
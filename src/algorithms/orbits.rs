@@ -35,7 +35,7 @@ pub fn count_orbits(orbits: &[(String, String)]) -> usize {
 
 pub fn orbit_distance(orbits: &[(String, String)], x: &str, y: &str) -> usize {
     let map = orbit_pairs_to_map(orbits);
-    tree_distance(&map, &map[x], &map[y])
+    tree_distance(&map, map[x], map[y])
 }
 
 pub fn tree_distance(tree: &HashMap<String, &str>, x: &str, y: &str) -> usize {
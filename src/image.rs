@@ -9,7 +9,7 @@ pub struct Image {
 
 impl Image {
     pub fn new(data: Vec<u8>, width: usize, height: usize) -> Option<Self> {
-        if data.len() % (width * height) == 0 {
+        if data.len().is_multiple_of(width * height) {
             Some(Image {
                 data,
                 width,
@@ -32,7 +32,7 @@ impl Image {
         Self::new(data, width, height).ok_or(ParseImageError)
     }
 
-    pub fn get_layers(&self) -> impl Iterator<Item = Layer> {
+    pub fn get_layers(&self) -> impl Iterator<Item = Layer<'_>> {
         self.data
             .chunks_exact(self.width * self.height)
             .map(|data| Layer { data })
@@ -48,8 +48,7 @@ impl Image {
                 .map(|i| {
                     self.get_layers()
                         .map(|layer| layer.data[i])
-                        .skip_while(|&n| n == 2)
-                        .next()
+                        .find(|&n| n != 2)
                         .unwrap_or(2)
                 })
                 .collect(),
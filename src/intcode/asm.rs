@@ -0,0 +1,532 @@
+use super::disasm::{Instruction, Mode, Op, Operand, Param};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub col: usize,
+    pub kind: AsmErrorKind,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AsmErrorKind {
+    Expected(&'static str),
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    OperandCount { expected: usize, found: usize },
+    ImmediateDestination,
+    DuplicateLabel(String),
+    UndefinedLabel(String),
+    AddressMismatch { expected: usize, actual: usize },
+    Overflow,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.col)?;
+        match &self.kind {
+            AsmErrorKind::Expected(what) => write!(f, "expected {}", what),
+            AsmErrorKind::UnknownMnemonic(m) => write!(f, "unknown mnemonic `{}`", m),
+            AsmErrorKind::UnknownDirective(d) => write!(f, "unknown directive `.{}`", d),
+            AsmErrorKind::OperandCount { expected, found } => {
+                write!(f, "expected {} operands, found {}", expected, found)
+            }
+            AsmErrorKind::ImmediateDestination => {
+                write!(f, "destination operand cannot be immediate")
+            }
+            AsmErrorKind::DuplicateLabel(l) => write!(f, "label `{}` is already defined", l),
+            AsmErrorKind::UndefinedLabel(l) => write!(f, "label `{}` is not defined", l),
+            AsmErrorKind::AddressMismatch { expected, actual } => write!(
+                f,
+                "address annotation {} does not match actual address {}",
+                expected, actual
+            ),
+            AsmErrorKind::Overflow => write!(f, "value does not fit in 64 bits"),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Num(i64),
+    Label(String),
+}
+
+#[derive(Debug, Clone)]
+struct Expr {
+    col: usize,
+    terms: Vec<(bool, Term, usize)>,
+}
+
+#[derive(Debug)]
+struct ParsedOperand {
+    col: usize,
+    mode: Mode,
+    expr: Expr,
+}
+
+#[derive(Debug)]
+enum Statement {
+    Instruction {
+        op: Op,
+        operands: Vec<ParsedOperand>,
+    },
+    Data(Vec<Expr>),
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    line: usize,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str, line: usize) -> Self {
+        Parser { src, line, pos: 0 }
+    }
+
+    fn col(&self) -> usize {
+        self.pos + 1
+    }
+
+    fn error(&self, kind: AsmErrorKind) -> AsmError {
+        self.error_at(self.col(), kind)
+    }
+
+    fn error_at(&self, col: usize, kind: AsmErrorKind) -> AsmError {
+        AsmError {
+            line: self.line,
+            col,
+            kind,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn skip_ws(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_ws();
+        self.rest().is_empty() || self.rest().starts_with(';')
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_ws();
+        if self.rest().starts_with(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char, what: &'static str) -> Result<(), AsmError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(AsmErrorKind::Expected(what)))
+        }
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, pred: F) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !pred(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn ident(&mut self) -> Option<&'a str> {
+        self.skip_ws();
+        if self
+            .rest()
+            .starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        {
+            Some(self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+        } else {
+            None
+        }
+    }
+
+    // Numbers are parsed together with a preceding minus sign, so that
+    // `-9223372036854775808` fits.
+    fn term(&mut self, neg: bool) -> Result<(bool, Term), AsmError> {
+        self.skip_ws();
+        let col = self.col();
+        if let Some(name) = self.ident() {
+            return Ok((neg, Term::Label(name.to_string())));
+        }
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        if digits.is_empty() {
+            return Err(self.error(AsmErrorKind::Expected("number or label")));
+        }
+        let parsed = if neg {
+            format!("-{}", digits).parse()
+        } else {
+            digits.parse()
+        };
+        parsed
+            .map(|n| (false, Term::Num(n)))
+            .map_err(|_| self.error_at(col, AsmErrorKind::Overflow))
+    }
+
+    fn expr(&mut self) -> Result<Expr, AsmError> {
+        self.skip_ws();
+        let col = self.col();
+        let mut terms = Vec::new();
+        let mut neg = self.eat('-');
+        loop {
+            self.skip_ws();
+            let term_col = self.col();
+            let (neg_term, term) = self.term(neg)?;
+            terms.push((neg_term, term, term_col));
+            if self.eat('+') {
+                neg = self.eat('-');
+            } else if self.eat('-') {
+                neg = true;
+            } else {
+                return Ok(Expr { col, terms });
+            }
+        }
+    }
+
+    fn operand(&mut self) -> Result<ParsedOperand, AsmError> {
+        self.skip_ws();
+        let col = self.col();
+        if self.eat('#') {
+            return Ok(ParsedOperand {
+                col,
+                mode: Mode::Immediate,
+                expr: self.expr()?,
+            });
+        }
+        self.expect('[', "operand")?;
+        self.skip_ws();
+        let save = self.pos;
+        let ret = if self.ident() == Some("rb") {
+            self.skip_ws();
+            let expr_col = self.col();
+            let expr = if self.rest().starts_with(']') {
+                Expr {
+                    col: expr_col,
+                    terms: vec![(false, Term::Num(0), expr_col)],
+                }
+            } else if self.rest().starts_with(['+', '-']) {
+                self.eat('+');
+                self.expr()?
+            } else {
+                return Err(self.error(AsmErrorKind::Expected("`+`, `-` or `]`")));
+            };
+            ParsedOperand {
+                col,
+                mode: Mode::Relative,
+                expr,
+            }
+        } else {
+            self.pos = save;
+            ParsedOperand {
+                col,
+                mode: Mode::Position,
+                expr: self.expr()?,
+            }
+        };
+        self.expect(']', "`]`")?;
+        Ok(ret)
+    }
+
+    fn list<T, F>(&mut self, mut item: F) -> Result<Vec<T>, AsmError>
+    where
+        F: FnMut(&mut Self) -> Result<T, AsmError>,
+    {
+        let mut ret = Vec::new();
+        if self.at_end() {
+            return Ok(ret);
+        }
+        loop {
+            ret.push(item(self)?);
+            if !self.eat(',') {
+                return Ok(ret);
+            }
+        }
+    }
+
+    fn statement(&mut self) -> Result<Option<Statement>, AsmError> {
+        if self.at_end() {
+            return Ok(None);
+        }
+        let col = self.col();
+        if self.eat('.') {
+            let name = self
+                .ident()
+                .ok_or_else(|| self.error(AsmErrorKind::Expected("directive")))?;
+            if name != "data" {
+                return Err(self.error_at(col, AsmErrorKind::UnknownDirective(name.to_string())));
+            }
+            return Ok(Some(Statement::Data(self.list(Self::expr)?)));
+        }
+        let mnemonic = self
+            .ident()
+            .ok_or_else(|| self.error(AsmErrorKind::Expected("mnemonic or label")))?;
+        let op = Op::from_mnemonic(mnemonic).ok_or_else(|| {
+            self.error_at(col, AsmErrorKind::UnknownMnemonic(mnemonic.to_string()))
+        })?;
+        let operands = self.list(Self::operand)?;
+        let expected = op.params().len();
+        if operands.len() != expected {
+            return Err(self.error_at(
+                col,
+                AsmErrorKind::OperandCount {
+                    expected,
+                    found: operands.len(),
+                },
+            ));
+        }
+        Ok(Some(Statement::Instruction { op, operands }))
+    }
+
+    fn label(&mut self) -> Option<(&'a str, usize)> {
+        self.skip_ws();
+        let save = self.pos;
+        let col = self.col();
+        let name = self
+            .ident()
+            .or_else(|| Some(self.take_while(|c| c.is_ascii_digit())).filter(|s| !s.is_empty()));
+        match name {
+            Some(name) if self.eat(':') => Some((name, col)),
+            _ => {
+                self.pos = save;
+                None
+            }
+        }
+    }
+}
+
+impl Statement {
+    fn len(&self) -> usize {
+        match self {
+            Statement::Instruction { op, .. } => 1 + op.params().len(),
+            Statement::Data(values) => values.len(),
+        }
+    }
+}
+
+struct Assembler {
+    labels: HashMap<String, i64>,
+}
+
+impl Assembler {
+    fn eval(&self, line: usize, expr: &Expr) -> Result<i64, AsmError> {
+        let error = |col, kind| AsmError { line, col, kind };
+        expr.terms.iter().try_fold(0_i64, |acc, (neg, term, col)| {
+            let val = match term {
+                Term::Num(n) => *n,
+                Term::Label(l) => *self
+                    .labels
+                    .get(l)
+                    .ok_or_else(|| error(*col, AsmErrorKind::UndefinedLabel(l.clone())))?,
+            };
+            if *neg {
+                acc.checked_sub(val)
+            } else {
+                acc.checked_add(val)
+            }
+            .ok_or_else(|| error(expr.col, AsmErrorKind::Overflow))
+        })
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<i64>, AsmError> {
+    let mut asm = Assembler {
+        labels: HashMap::new(),
+    };
+    let mut statements = Vec::new();
+    let mut addr = 0;
+    for (idx, text) in source.lines().enumerate() {
+        let mut parser = Parser::new(text, idx + 1);
+        while let Some((name, col)) = parser.label() {
+            if let Ok(expected) = name.parse::<usize>() {
+                if expected != addr {
+                    return Err(parser.error_at(
+                        col,
+                        AsmErrorKind::AddressMismatch {
+                            expected,
+                            actual: addr,
+                        },
+                    ));
+                }
+            } else if asm.labels.insert(name.to_string(), addr as i64).is_some() {
+                return Err(parser.error_at(col, AsmErrorKind::DuplicateLabel(name.to_string())));
+            }
+        }
+        if let Some(statement) = parser.statement()? {
+            if !parser.at_end() {
+                return Err(parser.error(AsmErrorKind::Expected("end of line")));
+            }
+            addr += statement.len();
+            statements.push((idx + 1, statement));
+        }
+    }
+
+    let mut code = Vec::with_capacity(addr);
+    for (line, statement) in statements {
+        match statement {
+            Statement::Instruction { op, operands } => {
+                let values = operands
+                    .iter()
+                    .map(|o| {
+                        Ok(Operand {
+                            mode: o.mode,
+                            value: asm.eval(line, &o.expr)?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let instr = Instruction::new(op, &values).ok_or_else(|| {
+                    let col = operands
+                        .iter()
+                        .zip(op.params())
+                        .find(|(o, &param)| param == Param::Addr && o.mode == Mode::Immediate)
+                        .map_or(1, |(o, _)| o.col);
+                    AsmError {
+                        line,
+                        col,
+                        kind: AsmErrorKind::ImmediateDestination,
+                    }
+                })?;
+                code.extend(instr.encode());
+            }
+            Statement::Data(values) => {
+                for expr in &values {
+                    code.push(asm.eval(line, expr)?);
+                }
+            }
+        }
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::disasm::disassemble;
+    use super::*;
+
+    #[test]
+    fn asm_modes_and_labels() {
+        assert_eq!(
+            assemble(
+                "
+                start: add [rb+3], #5, [x] ; comment
+                       out [rb - 4 + 2]
+                       jt #1, #start
+                x:     .data 7
+                "
+            )
+            .unwrap(),
+            &[1201, 3, 5, 9, 204, -2, 1105, 1, 0, 7]
+        );
+    }
+
+    #[test]
+    fn asm_data_block_and_expressions() {
+        assert_eq!(
+            assemble(
+                "
+                out [table+1]
+                end
+                table: .data 10, -20, table - 1, end_ + 2
+                end_:
+                "
+            )
+            .unwrap(),
+            &[4, 4, 99, 10, -20, 2, 9]
+        );
+    }
+
+    #[test]
+    fn asm_round_trip_disassembly() {
+        let code = vec![
+            109, 19, 204, -34, 21101, 3, -5, 7, 11101, 1105, 0, 5, 1002, 4, 3, 4, 99, -1,
+        ];
+        let listing = disassemble(&code).to_string();
+        assert_eq!(assemble(&listing).unwrap(), code);
+    }
+
+    #[test]
+    fn asm_round_trip_extremes() {
+        let code = vec![
+            204,
+            i64::MIN,
+            1001,
+            i64::MIN,
+            i64::MAX,
+            0,
+            i64::MIN,
+            i64::MAX,
+        ];
+        let listing = disassemble(&code).to_string();
+        assert!(listing.contains("[rb-9223372036854775808]"));
+        assert_eq!(assemble(&listing).unwrap(), code);
+        assert_eq!(
+            assemble(".data 1 - 9223372036854775808").unwrap(),
+            &[i64::MIN + 1]
+        );
+        assert_eq!(
+            assemble(".data -9223372036854775809").unwrap_err().kind,
+            AsmErrorKind::Overflow
+        );
+    }
+
+    #[test]
+    fn asm_errors() {
+        let err = |src| assemble(src).unwrap_err();
+        assert_eq!(
+            err("add #1, #2, [3]\n  frob [1]"),
+            AsmError {
+                line: 2,
+                col: 3,
+                kind: AsmErrorKind::UnknownMnemonic("frob".into())
+            }
+        );
+        assert_eq!(
+            err("in #4"),
+            AsmError {
+                line: 1,
+                col: 4,
+                kind: AsmErrorKind::ImmediateDestination
+            }
+        );
+        assert_eq!(err("add #1, #2, #3").col, 13);
+        assert_eq!(
+            err("out [missing]"),
+            AsmError {
+                line: 1,
+                col: 6,
+                kind: AsmErrorKind::UndefinedLabel("missing".into())
+            }
+        );
+        assert_eq!(
+            err("end\n3: end").kind,
+            AsmErrorKind::AddressMismatch {
+                expected: 3,
+                actual: 1
+            }
+        );
+        assert_eq!(
+            err("jt #1").kind,
+            AsmErrorKind::OperandCount {
+                expected: 2,
+                found: 1
+            }
+        );
+        assert_eq!(
+            err("a: end\na: end").to_string(),
+            "2:1: label `a` is already defined"
+        );
+    }
+}
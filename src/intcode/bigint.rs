@@ -0,0 +1,284 @@
+use super::cell::Cell;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+const BASE: u64 = 1 << 32;
+
+// Sign and magnitude, with the magnitude in little-endian base 2^32 digits and
+// no trailing zero digits. Zero is never negative.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    digits: Vec<u32>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ParseBigIntError;
+
+impl fmt::Display for ParseBigIntError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid integer")
+    }
+}
+
+impl std::error::Error for ParseBigIntError {}
+
+fn trim(digits: &mut Vec<u32>) {
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+}
+
+fn cmp_magnitude(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut ret = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0;
+    for idx in 0..a.len().max(b.len()) {
+        let sum =
+            u64::from(*a.get(idx).unwrap_or(&0)) + u64::from(*b.get(idx).unwrap_or(&0)) + carry;
+        ret.push(sum as u32);
+        carry = sum >> 32;
+    }
+    if carry != 0 {
+        ret.push(carry as u32);
+    }
+    ret
+}
+
+// Requires |a| >= |b|.
+fn sub_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut ret = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    for (idx, &digit) in a.iter().enumerate() {
+        let sub = u64::from(*b.get(idx).unwrap_or(&0)) + borrow;
+        let digit = u64::from(digit);
+        if digit >= sub {
+            ret.push((digit - sub) as u32);
+            borrow = 0;
+        } else {
+            ret.push((digit + BASE - sub) as u32);
+            borrow = 1;
+        }
+    }
+    trim(&mut ret);
+    ret
+}
+
+impl BigInt {
+    fn new(negative: bool, mut digits: Vec<u32>) -> Self {
+        trim(&mut digits);
+        BigInt {
+            negative: negative && !digits.is_empty(),
+            digits,
+        }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    fn mul_small_add(&mut self, mul: u32, add: u32) {
+        let mut carry = u64::from(add);
+        for digit in &mut self.digits {
+            let val = u64::from(*digit) * u64::from(mul) + carry;
+            *digit = val as u32;
+            carry = val >> 32;
+        }
+        if carry != 0 {
+            self.digits.push(carry as u32);
+        }
+    }
+
+    fn div_small(&mut self, div: u32) -> u32 {
+        let mut rem = 0;
+        for digit in self.digits.iter_mut().rev() {
+            let val = (rem << 32) | u64::from(*digit);
+            *digit = (val / u64::from(div)) as u32;
+            rem = val % u64::from(div);
+        }
+        trim(&mut self.digits);
+        rem as u32
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(val: i64) -> Self {
+        let mag = val.unsigned_abs();
+        BigInt::new(val < 0, vec![mag as u32, (mag >> 32) as u32])
+    }
+}
+
+impl FromStr for BigInt {
+    type Err = ParseBigIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        if digits.is_empty() {
+            return Err(ParseBigIntError);
+        }
+        let mut ret = BigInt::default();
+        for c in digits.chars() {
+            let digit = c.to_digit(10).ok_or(ParseBigIntError)?;
+            ret.mul_small_add(10, digit);
+        }
+        Ok(BigInt::new(negative, ret.digits))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.digits.is_empty() {
+            return write!(f, "0");
+        }
+        let mut rest = self.clone();
+        let mut chunks = Vec::new();
+        while !rest.digits.is_empty() {
+            chunks.push(rest.div_small(1_000_000_000));
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap())?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_magnitude(&self.digits, &other.digits),
+            (true, true) => cmp_magnitude(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Cell for BigInt {
+    fn to_i64(&self) -> Option<i64> {
+        if self.digits.len() > 2 {
+            return None;
+        }
+        let mag = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0_u64, |acc, &digit| (acc << 32) | u64::from(digit));
+        if self.negative {
+            0_i64.checked_sub_unsigned(mag)
+        } else {
+            i64::try_from(mag).ok()
+        }
+    }
+
+    fn checked_add(&self, other: &Self) -> Option<Self> {
+        Some(self.wrapping_add(other))
+    }
+
+    fn checked_mul(&self, other: &Self) -> Option<Self> {
+        Some(self.wrapping_mul(other))
+    }
+
+    fn wrapping_add(&self, other: &Self) -> Self {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_magnitude(&self.digits, &other.digits));
+        }
+        match cmp_magnitude(&self.digits, &other.digits) {
+            Ordering::Less => {
+                BigInt::new(other.negative, sub_magnitude(&other.digits, &self.digits))
+            }
+            _ => BigInt::new(self.negative, sub_magnitude(&self.digits, &other.digits)),
+        }
+    }
+
+    fn wrapping_mul(&self, other: &Self) -> Self {
+        let mut ret = vec![0_u32; self.digits.len() + other.digits.len()];
+        for (i, &a) in self.digits.iter().enumerate() {
+            let mut carry = 0_u64;
+            for (j, &b) in other.digits.iter().enumerate() {
+                let val = u64::from(a) * u64::from(b) + u64::from(ret[i + j]) + carry;
+                ret[i + j] = val as u32;
+                carry = val >> 32;
+            }
+            let mut k = i + other.digits.len();
+            while carry != 0 {
+                let val = u64::from(ret[k]) + carry;
+                ret[k] = val as u32;
+                carry = val >> 32;
+                k += 1;
+            }
+        }
+        BigInt::new(self.negative != other.negative, ret)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(s: &str) -> BigInt {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn bigint_roundtrip() {
+        for s in &[
+            "0",
+            "-1",
+            "4294967296",
+            "-9223372036854775808",
+            "123456789012345678901234567890",
+        ] {
+            assert_eq!(big(s).to_string(), *s);
+        }
+        assert_eq!(big("-0"), BigInt::default());
+        assert_eq!(BigInt::from(i64::MIN).to_string(), i64::MIN.to_string());
+        assert!("12a".parse::<BigInt>().is_err() && "-".parse::<BigInt>().is_err());
+    }
+
+    #[test]
+    fn bigint_arithmetic() {
+        let a = big("99999999999999999999");
+        let b = big("-100000000000000000000");
+        assert_eq!(a.wrapping_add(&b), big("-1"));
+        assert_eq!(b.wrapping_add(&a), big("-1"));
+        assert_eq!(a.wrapping_add(&a), big("199999999999999999998"));
+        assert_eq!(
+            a.wrapping_mul(&b),
+            big("-9999999999999999999900000000000000000000")
+        );
+        assert_eq!(big("-3").wrapping_mul(&big("-7")), big("21"));
+        assert!(b < a && big("-2") < big("-1") && big("1") > BigInt::default());
+    }
+
+    #[test]
+    fn bigint_to_i64() {
+        assert_eq!(BigInt::from(i64::MAX).to_i64(), Some(i64::MAX));
+        assert_eq!(BigInt::from(i64::MIN).to_i64(), Some(i64::MIN));
+        assert_eq!(big("9223372036854775808").to_i64(), None);
+        assert_eq!(big("-9223372036854775809").to_i64(), None);
+    }
+}
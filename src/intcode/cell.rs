@@ -0,0 +1,52 @@
+use std::fmt;
+use std::hash::Hash;
+
+// The value stored in one memory cell. Addresses, parameter modes and the
+// relative base are always plain `i64`s, so cells only need to convert to one
+// when used as such.
+pub trait Cell:
+    Clone + fmt::Debug + fmt::Display + Default + Eq + Ord + Hash + From<i64> + Send + Sync + 'static
+{
+    fn to_i64(&self) -> Option<i64>;
+    fn checked_add(&self, other: &Self) -> Option<Self>;
+    fn checked_mul(&self, other: &Self) -> Option<Self>;
+    fn wrapping_add(&self, other: &Self) -> Self;
+    fn wrapping_mul(&self, other: &Self) -> Self;
+
+    fn is_zero(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Cell for i64 {
+    fn to_i64(&self) -> Option<i64> {
+        Some(*self)
+    }
+
+    fn checked_add(&self, other: &Self) -> Option<Self> {
+        i64::checked_add(*self, *other)
+    }
+
+    fn checked_mul(&self, other: &Self) -> Option<Self> {
+        i64::checked_mul(*self, *other)
+    }
+
+    fn wrapping_add(&self, other: &Self) -> Self {
+        i64::wrapping_add(*self, *other)
+    }
+
+    fn wrapping_mul(&self, other: &Self) -> Self {
+        i64::wrapping_mul(*self, *other)
+    }
+
+    fn is_zero(&self) -> bool {
+        *self == 0
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Arithmetic {
+    #[default]
+    Wrapping,
+    Checked,
+}
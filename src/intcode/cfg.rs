@@ -0,0 +1,458 @@
+use super::disasm::{Instruction, Mode, Op, Operand};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+// The statically reachable part of a program. Jumps through memory make every
+// constant stored to memory that decodes to code a potential target; such a
+// candidate is only kept if all code reachable from it decodes and none of it is the
+// destination of a fixed-address write, since otherwise it is most likely data.
+#[derive(Debug, Clone, Default)]
+pub struct Discovery {
+    pub instructions: BTreeMap<usize, Instruction>,
+    pub leaders: BTreeSet<usize>,
+    pub invalid: BTreeSet<usize>,
+    pub indirect: bool,
+}
+
+// Whether a conditional jump is always (Some(true)) or never (Some(false)) taken.
+fn branch_taken(op: Op, cond: Operand) -> Option<bool> {
+    match cond.mode {
+        Mode::Immediate => Some((cond.value != 0) == (op == Op::Jt)),
+        _ => None,
+    }
+}
+
+fn successors(addr: usize, inst: &Instruction) -> (Vec<usize>, bool) {
+    let next = addr + inst.len();
+    let [cond, dest] = match inst.op {
+        Op::End => return (vec![], false),
+        Op::Jt | Op::Jf => [inst.operands()[0], inst.operands()[1]],
+        _ => return (vec![next], false),
+    };
+    let mut ret = Vec::new();
+    let taken = branch_taken(inst.op, cond);
+    if taken != Some(true) {
+        ret.push(next);
+    }
+    if taken == Some(false) {
+        return (ret, false);
+    }
+    match dest.mode {
+        Mode::Immediate if dest.value >= 0 => ret.push(dest.value as usize),
+        Mode::Immediate => {}
+        _ => return (ret, true),
+    }
+    (ret, false)
+}
+
+pub(super) fn static_write(inst: &Instruction) -> Option<usize> {
+    let dest = match inst.op {
+        Op::Add | Op::Mul | Op::Lt | Op::Eq => inst.operands()[2],
+        Op::In => inst.operands()[0],
+        _ => return None,
+    };
+    match dest.mode {
+        Mode::Position if dest.value >= 0 => Some(dest.value as usize),
+        _ => None,
+    }
+}
+
+impl Discovery {
+    fn explore(&mut self, code: &[i64], start: usize) {
+        let mut work = vec![start];
+        while let Some(addr) = work.pop() {
+            if self.instructions.contains_key(&addr) || self.invalid.contains(&addr) {
+                continue;
+            }
+            let inst = match Instruction::decode(code, addr) {
+                Some(inst) => inst,
+                None => {
+                    self.invalid.insert(addr);
+                    continue;
+                }
+            };
+            self.instructions.insert(addr, inst);
+            let (next, indirect) = successors(addr, &inst);
+            self.indirect |= indirect;
+            if inst.op == Op::Jt || inst.op == Op::Jf {
+                self.leaders.extend(&next);
+            }
+            work.extend(next);
+        }
+    }
+
+    pub fn new(code: &[i64]) -> Self {
+        let mut ret = Discovery::default();
+        ret.leaders.insert(0);
+        ret.explore(code, 0);
+        if !ret.indirect {
+            return ret;
+        }
+        loop {
+            let written: BTreeSet<_> = ret.instructions.values().filter_map(static_write).collect();
+            let candidates: BTreeSet<usize> = ret
+                .instructions
+                .values()
+                .filter_map(stored_constant)
+                .filter(|&val| val >= 0 && (val as usize) < code.len())
+                .map(|val| val as usize)
+                .filter(|addr| !ret.leaders.contains(addr))
+                .collect();
+            let mut changed = false;
+            for addr in candidates {
+                let mut attempt = ret.clone();
+                attempt.leaders.insert(addr);
+                attempt.explore(code, addr);
+                let plausible = attempt.invalid.len() == ret.invalid.len()
+                    && !attempt.code_cells().any(|cell| written.contains(&cell));
+                if plausible {
+                    ret = attempt;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return ret;
+            }
+        }
+    }
+
+    // Reachable words that fail to decode count as code too: a program that
+    // patches them before getting there is modifying itself.
+    pub fn code_cells(&self) -> impl Iterator<Item = usize> + '_ {
+        self.instructions
+            .iter()
+            .flat_map(|(&addr, inst)| addr..addr + inst.len())
+            .chain(self.invalid.iter().copied())
+    }
+
+    pub fn code_ranges(&self) -> Vec<(usize, usize)> {
+        let cells: BTreeSet<_> = self.code_cells().collect();
+        let mut ret: Vec<(usize, usize)> = Vec::new();
+        for addr in cells {
+            match ret.last_mut() {
+                Some((_, last)) if addr == *last + 1 => *last = addr,
+                _ => ret.push((addr, addr)),
+            }
+        }
+        ret
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Terminator {
+    Fallthrough(usize),
+    Jump(usize),
+    Branch { taken: usize, not_taken: usize },
+    // An unconditional jump preceded by storing the address to come back to
+    // in the current stack frame.
+    Call { target: usize, ret: usize },
+    // An unconditional jump through a stack slot.
+    Return,
+    Indirect { not_taken: Option<usize> },
+    Halt,
+    Invalid(usize),
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<usize> {
+        match *self {
+            Terminator::Fallthrough(to) | Terminator::Jump(to) => vec![to],
+            Terminator::Branch { taken, not_taken } => vec![taken, not_taken],
+            Terminator::Call { target, ret } => vec![target, ret],
+            Terminator::Indirect { not_taken } => not_taken.into_iter().collect(),
+            Terminator::Return | Terminator::Halt | Terminator::Invalid(_) => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+    pub instructions: Vec<(usize, Instruction)>,
+    pub terminator: Terminator,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Function {
+    pub entry: usize,
+    pub blocks: BTreeSet<usize>,
+    pub call_sites: BTreeSet<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: BTreeMap<usize, Block>,
+    pub functions: BTreeMap<usize, Function>,
+    pub unreachable: Vec<(usize, usize)>,
+}
+
+// The value an instruction stores if it only combines immediates, as in
+// `add #ret, #0, [rb+1]`.
+fn stored_constant(inst: &Instruction) -> Option<i64> {
+    let args = inst.operands();
+    match inst.op {
+        Op::Add | Op::Mul if args[0].mode == Mode::Immediate && args[1].mode == Mode::Immediate => {
+            Some(if inst.op == Op::Add {
+                args[0].value.wrapping_add(args[1].value)
+            } else {
+                args[0].value.wrapping_mul(args[1].value)
+            })
+        }
+        _ => None,
+    }
+}
+
+fn stacked_constant(inst: &Instruction) -> Option<i64> {
+    match inst.operands().get(2) {
+        Some(dest) if dest.mode == Mode::Relative => stored_constant(inst),
+        _ => None,
+    }
+}
+
+fn terminator(
+    found: &Discovery,
+    body: &[(usize, Instruction)],
+    addr: usize,
+    inst: &Instruction,
+) -> Terminator {
+    let next = addr + inst.len();
+    let [cond, dest] = [inst.operands()[0], inst.operands()[1]];
+    let taken = branch_taken(inst.op, cond);
+    if taken == Some(false) {
+        return Terminator::Fallthrough(next);
+    }
+    match dest.mode {
+        Mode::Immediate if dest.value < 0 => Terminator::Invalid(addr),
+        Mode::Immediate if taken.is_none() => Terminator::Branch {
+            taken: dest.value as usize,
+            not_taken: next,
+        },
+        Mode::Immediate => {
+            let ret = body
+                .iter()
+                .rev()
+                .filter_map(|(_, inst)| stacked_constant(inst))
+                .find(|&val| val >= 0 && found.instructions.contains_key(&(val as usize)));
+            match ret {
+                Some(ret) => Terminator::Call {
+                    target: dest.value as usize,
+                    ret: ret as usize,
+                },
+                None => Terminator::Jump(dest.value as usize),
+            }
+        }
+        Mode::Relative if taken.is_some() => Terminator::Return,
+        _ => Terminator::Indirect {
+            not_taken: if taken.is_none() { Some(next) } else { None },
+        },
+    }
+}
+
+impl Cfg {
+    pub fn new(code: &[i64]) -> Self {
+        let found = Discovery::new(code);
+        let mut blocks = BTreeMap::new();
+        for &start in &found.leaders {
+            let mut addr = start;
+            let mut body = Vec::new();
+            let terminator = loop {
+                let inst = match found.instructions.get(&addr) {
+                    Some(inst) => *inst,
+                    None => break Terminator::Invalid(addr),
+                };
+                body.push((addr, inst));
+                match inst.op {
+                    Op::End => break Terminator::Halt,
+                    Op::Jt | Op::Jf => break terminator(&found, &body, addr, &inst),
+                    _ => {}
+                }
+                addr += inst.len();
+                if found.leaders.contains(&addr) {
+                    break Terminator::Fallthrough(addr);
+                }
+            };
+            let end = body.last().map_or(start, |(addr, inst)| addr + inst.len());
+            blocks.insert(
+                start,
+                Block {
+                    start,
+                    end,
+                    instructions: body,
+                    terminator,
+                },
+            );
+        }
+
+        let cells: BTreeSet<_> = found.code_cells().collect();
+        let mut unreachable: Vec<(usize, usize)> = Vec::new();
+        for addr in (0..code.len()).filter(|addr| !cells.contains(addr)) {
+            match unreachable.last_mut() {
+                Some((_, last)) if addr == *last + 1 => *last = addr,
+                _ => unreachable.push((addr, addr)),
+            }
+        }
+
+        let mut cfg = Cfg {
+            blocks,
+            functions: BTreeMap::new(),
+            unreachable,
+        };
+        let entries: BTreeSet<_> = cfg
+            .blocks
+            .values()
+            .filter_map(|block| match block.terminator {
+                Terminator::Call { target, .. } => Some(target),
+                _ => None,
+            })
+            .collect();
+        for entry in entries {
+            let function = cfg.function_from(entry);
+            cfg.functions.insert(entry, function);
+        }
+        cfg
+    }
+
+    // Collects the blocks of the function starting at `entry`, stepping over
+    // calls to their return address rather than into the callee.
+    fn function_from(&self, entry: usize) -> Function {
+        let mut function = Function {
+            entry,
+            blocks: BTreeSet::new(),
+            call_sites: BTreeSet::new(),
+        };
+        let mut work = vec![entry];
+        while let Some(start) = work.pop() {
+            let block = match self.blocks.get(&start) {
+                Some(block) if function.blocks.insert(start) => block,
+                _ => continue,
+            };
+            match block.terminator {
+                Terminator::Call { ret, .. } => {
+                    function.call_sites.insert(start);
+                    work.push(ret);
+                }
+                terminator => work.extend(terminator.successors()),
+            }
+        }
+        function
+    }
+
+    pub fn block_containing(&self, addr: usize) -> Option<&Block> {
+        self.blocks
+            .range(..=addr)
+            .next_back()
+            .map(|(_, block)| block)
+            .filter(|block| addr < block.end)
+    }
+
+    pub fn write_dot<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];")?;
+        for (start, block) in &self.blocks {
+            let mut label = String::new();
+            if self.functions.contains_key(start) {
+                label.push_str(&format!("fn_{}:\\l", start));
+            }
+            for (addr, inst) in &block.instructions {
+                label.push_str(&format!("{:>5}: {}\\l", addr, inst));
+            }
+            match block.terminator {
+                Terminator::Invalid(addr) => label.push_str(&format!("{:>5}: <invalid>\\l", addr)),
+                Terminator::Return => label.push_str("<return>\\l"),
+                Terminator::Indirect { .. } => label.push_str("<indirect jump>\\l"),
+                _ => {}
+            }
+            writeln!(out, "    b{} [label=\"{}\"];", start, label)?;
+            let edges: Vec<(usize, &str)> = match block.terminator {
+                Terminator::Fallthrough(to) | Terminator::Jump(to) => vec![(to, "")],
+                Terminator::Branch { taken, not_taken } => {
+                    vec![
+                        (taken, " [label=\"taken\"]"),
+                        (not_taken, " [style=dashed]"),
+                    ]
+                }
+                Terminator::Call { target, ret } => vec![
+                    (target, " [label=\"call\", color=blue]"),
+                    (ret, " [style=dotted]"),
+                ],
+                Terminator::Indirect { not_taken } => not_taken
+                    .map(|to| (to, " [style=dashed]"))
+                    .into_iter()
+                    .collect(),
+                _ => vec![],
+            };
+            for (to, attrs) in edges {
+                writeln!(out, "    b{} -> b{}{};", start, to, attrs)?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::super::fixtures::COUNTDOWN;
+    use super::*;
+
+    const CALLER: &str = "
+              mrb #100
+              add #ret, #0, [rb+0]
+              jt #1, #double
+        ret:  out [x]
+              end
+        double: mrb #1
+              mul [x], #2, [x]
+              jf [x], #done
+              add [x], #1, [x]
+        done: mrb #-1
+              jt #1, [rb+0]
+        x:    .data 21
+              .data 7, 7
+    ";
+
+    #[test]
+    fn cfg_blocks_and_calls() {
+        let code = assemble(CALLER).unwrap();
+        let cfg = Cfg::new(&code);
+        let starts: Vec<_> = cfg.blocks.keys().copied().collect();
+        assert_eq!(starts, &[0, 9, 12, 21, 25]);
+        assert_eq!(
+            cfg.blocks[&0].terminator,
+            Terminator::Call { target: 12, ret: 9 }
+        );
+        assert_eq!(cfg.blocks[&9].terminator, Terminator::Halt);
+        assert_eq!(
+            cfg.blocks[&12].terminator,
+            Terminator::Branch {
+                taken: 25,
+                not_taken: 21
+            }
+        );
+        assert_eq!(cfg.blocks[&21].terminator, Terminator::Fallthrough(25));
+        assert_eq!(cfg.blocks[&25].terminator, Terminator::Return);
+        assert_eq!(cfg.block_containing(17).map(|b| b.start), Some(12));
+        assert!(cfg.block_containing(30).is_none());
+
+        let double = &cfg.functions[&12];
+        assert_eq!(double.blocks, [12, 21, 25].iter().copied().collect());
+        assert!(double.call_sites.is_empty());
+        assert_eq!(cfg.unreachable, &[(30, 32)]);
+    }
+
+    #[test]
+    fn cfg_dot() {
+        let code = assemble(COUNTDOWN).unwrap();
+        let mut dot = Vec::new();
+        Cfg::new(&code).write_dot(&mut dot).unwrap();
+        assert_eq!(
+            String::from_utf8(dot).unwrap(),
+            "digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n    \
+             b0 [label=\"    0: out [10]\\l    2: add [10], #-1, [10]\\l    6: jt [10], #0\\l\"];\n    \
+             b0 -> b0 [label=\"taken\"];\n    \
+             b0 -> b9 [style=dashed];\n    \
+             b9 [label=\"    9: end\\l\"];\n}\n"
+        );
+    }
+}
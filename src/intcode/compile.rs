@@ -0,0 +1,412 @@
+use super::cfg::{static_write, Discovery};
+use super::disasm::{Mode, Op, Operand};
+use super::memory::DEFAULT_LIMIT;
+use std::collections::BTreeSet;
+use std::fmt::{self, Write};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CompileError {
+    SelfModifying { ip: usize, addr: usize },
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::SelfModifying { ip, addr } => write!(
+                f,
+                "instruction at address {} writes to code at address {}",
+                ip, addr
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+// Translates a program into standalone Rust source exposing
+// `pub fn run(input: impl Iterator<Item = i64>, output: impl FnMut(i64))`.
+// Writes to code at a fixed address are rejected up front; writes through the
+// relative base are checked at runtime and stop the program with an error.
+pub fn compile(code: &[i64]) -> Result<String, CompileError> {
+    let found = Discovery::new(code);
+    let cells: BTreeSet<_> = found.code_cells().collect();
+    for (&ip, inst) in &found.instructions {
+        if let Some(addr) = static_write(inst).filter(|addr| cells.contains(addr)) {
+            return Err(CompileError::SelfModifying { ip, addr });
+        }
+    }
+
+    let mut out = String::new();
+    emit_prelude(&mut out, code, &found).unwrap();
+    for &leader in &found.leaders {
+        emit_block(&mut out, code, &found, leader).unwrap();
+    }
+    out.push_str(
+        "            block => return Err(format!(\"no compiled code at address {}\", block)),\n\
+         \x20       };\n    }\n}\n",
+    );
+    Ok(out)
+}
+
+fn emit_prelude(out: &mut String, code: &[i64], found: &Discovery) -> fmt::Result {
+    writeln!(out, "// Generated from an Intcode program; do not edit.")?;
+    writeln!(out)?;
+    writeln!(out, "const IMAGE: [i64; {}] = [", code.len())?;
+    for chunk in code.chunks(16) {
+        let words: Vec<_> = chunk.iter().map(i64::to_string).collect();
+        writeln!(out, "    {},", words.join(", "))?;
+    }
+    writeln!(out, "];")?;
+    writeln!(out, "const MEMORY_LIMIT: usize = {};", DEFAULT_LIMIT)?;
+    out.push_str(
+        "
+#[allow(dead_code)]
+fn rd(mem: &[i64], addr: i64) -> Result<i64, String> {
+    if addr < 0 || addr as usize >= MEMORY_LIMIT {
+        return Err(format!(\"address {} is out of bounds\", addr));
+    }
+    Ok(mem.get(addr as usize).copied().unwrap_or(0))
+}
+
+#[allow(dead_code)]
+fn wr(mem: &mut Vec<i64>, addr: i64, val: i64) -> Result<(), String> {
+    if addr < 0 || addr as usize >= MEMORY_LIMIT {
+        return Err(format!(\"address {} is out of bounds\", addr));
+    }
+    if addr as usize >= mem.len() {
+        mem.resize(addr as usize + 1, 0);
+    }
+    mem[addr as usize] = val;
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn target(addr: i64) -> Result<usize, String> {
+    if addr < 0 || addr as usize >= IMAGE.len() {
+        return Err(format!(\"address {} is out of bounds\", addr));
+    }
+    Ok(addr as usize)
+}
+",
+    );
+    let ranges: Vec<_> = found
+        .code_ranges()
+        .iter()
+        .map(|(start, end)| format!("{}..={}", start, end))
+        .collect();
+    writeln!(out)?;
+    writeln!(out, "#[allow(dead_code)]")?;
+    writeln!(out, "fn is_code(addr: i64) -> bool {{")?;
+    if ranges.is_empty() {
+        writeln!(out, "    false")?;
+    } else {
+        writeln!(out, "    matches!(addr, {})", ranges.join(" | "))?;
+    }
+    writeln!(out, "}}")?;
+    out.push_str(
+        "
+#[allow(unused_mut, unused_variables, unreachable_code, clippy::all)]
+pub fn run<I, O>(mut input: I, mut output: O) -> Result<(), String>
+where
+    I: Iterator<Item = i64>,
+    O: FnMut(i64),
+{
+    let mut mem = IMAGE.to_vec();
+    let mut rb: i64 = 0;
+    let mut block: usize = 0;
+    loop {
+        block = match block {
+",
+    );
+    Ok(())
+}
+
+fn operand(arg: Operand) -> String {
+    match arg.mode {
+        Mode::Position => format!("rd(&mem, {})?", arg.value),
+        Mode::Immediate => format!("{}_i64", arg.value),
+        Mode::Relative => format!("rd(&mem, rb.wrapping_add({}))?", arg.value),
+    }
+}
+
+fn write_dest(out: &mut String, ip: usize, dest: Operand, val: &str) -> fmt::Result {
+    match dest.mode {
+        Mode::Relative => {
+            writeln!(
+                out,
+                "                let d = rb.wrapping_add({});",
+                dest.value
+            )?;
+            writeln!(out, "                if is_code(d) {{")?;
+            writeln!(
+                out,
+                "                    return Err(format!(\"self-modifying write to {{}} at address {}\", d));",
+                ip
+            )?;
+            writeln!(out, "                }}")?;
+            writeln!(out, "                wr(&mut mem, d, {})?;", val)
+        }
+        _ => writeln!(
+            out,
+            "                wr(&mut mem, {}, {})?;",
+            dest.value, val
+        ),
+    }
+}
+
+fn emit_block(out: &mut String, code: &[i64], found: &Discovery, leader: usize) -> fmt::Result {
+    writeln!(out, "            {} => {{", leader)?;
+    let mut addr = leader;
+    loop {
+        let inst = match found.instructions.get(&addr) {
+            Some(inst) => *inst,
+            None => {
+                match code.get(addr) {
+                    Some(word) => writeln!(
+                        out,
+                        "                return Err(\"invalid instruction {} at address {}\".to_string());",
+                        word, addr
+                    )?,
+                    None => writeln!(
+                        out,
+                        "                return Err(\"execution ran past the end of the program\".to_string());"
+                    )?,
+                }
+                break;
+            }
+        };
+        writeln!(out, "                // {:>5}: {}", addr, inst)?;
+        let args = inst.operands();
+        match inst.op {
+            Op::Add | Op::Mul | Op::Lt | Op::Eq => {
+                writeln!(out, "                let a = {};", operand(args[0]))?;
+                writeln!(out, "                let b = {};", operand(args[1]))?;
+                let val = match inst.op {
+                    Op::Add => "a.wrapping_add(b)",
+                    Op::Mul => "a.wrapping_mul(b)",
+                    Op::Lt => "(a < b) as i64",
+                    _ => "(a == b) as i64",
+                };
+                write_dest(out, addr, args[2], val)?;
+            }
+            Op::In => {
+                writeln!(
+                    out,
+                    "                let a = input.next().ok_or(\"input exhausted\")?;"
+                )?;
+                write_dest(out, addr, args[0], "a")?;
+            }
+            Op::Out => writeln!(out, "                output({});", operand(args[0]))?,
+            Op::Mrb => writeln!(
+                out,
+                "                rb = rb.wrapping_add({});",
+                operand(args[0])
+            )?,
+            Op::End => {
+                writeln!(out, "                return Ok(());")?;
+                break;
+            }
+            Op::Jt | Op::Jf => {
+                let next = addr + inst.len();
+                let dest = match args[1].mode {
+                    Mode::Immediate
+                        if args[1].value >= 0
+                            && found.leaders.contains(&(args[1].value as usize)) =>
+                    {
+                        args[1].value.to_string()
+                    }
+                    _ => format!("target({})?", operand(args[1])),
+                };
+                let cond = if inst.op == Op::Jt { "!=" } else { "==" };
+                writeln!(out, "                let a = {};", operand(args[0]))?;
+                writeln!(out, "                let b = {};", dest)?;
+                writeln!(
+                    out,
+                    "                if a {} 0 {{ b }} else {{ {} }}",
+                    cond, next
+                )?;
+                break;
+            }
+        }
+        addr += inst.len();
+        if found.leaders.contains(&addr) {
+            writeln!(out, "                {}", addr)?;
+            break;
+        }
+    }
+    writeln!(out, "            }}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::super::fixtures::COUNTDOWN;
+    use super::super::Machine;
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    // Removes the build directory even when an assertion fails.
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn compile_discovers_blocks() {
+        let code = assemble(COUNTDOWN).unwrap();
+        let found = Discovery::new(&code);
+        assert_eq!(found.leaders, [0, 9].iter().copied().collect());
+        assert_eq!(found.code_ranges(), &[(0, 9)]);
+        assert!(!found.indirect);
+
+        let source = compile(&code).unwrap();
+        assert!(source.contains("const IMAGE: [i64; 11] = ["));
+        assert!(source.contains("            0 => {\n                //     0: out [10]\n"));
+        assert!(source.contains("                if a != 0 { b } else { 9 }\n"));
+    }
+
+    #[test]
+    fn compile_follows_return_addresses() {
+        // Calls a subroutine that returns through the stack, so the address
+        // after the call is only known from the immediate pushed as return address.
+        let code = assemble(
+            "
+                  mrb #100
+                  add #ret, #0, [rb+0]
+                  jt #1, #double
+            ret:  out [x]
+                  end
+            double: mul [x], #2, [x]
+                  jt #1, [rb+0]
+            x:    .data 21
+            ",
+        )
+        .unwrap();
+        let found = Discovery::new(&code);
+        assert!(found.indirect);
+        assert!(found.leaders.contains(&9));
+        assert!(compile(&code)
+            .unwrap()
+            .contains("target(rd(&mem, rb.wrapping_add(0))?)?"));
+    }
+
+    #[test]
+    fn compile_rejects_self_modifying_code() {
+        assert_eq!(
+            compile(&[1, 0, 0, 3, 99]),
+            Err(CompileError::SelfModifying { ip: 0, addr: 3 })
+        );
+        assert_eq!(
+            compile(&[1101, 1, 1, 4, 99]).unwrap_err().to_string(),
+            "instruction at address 0 writes to code at address 4"
+        );
+        // Patches an invalid opcode into `end` before reaching it.
+        assert_eq!(
+            compile(&[1101, 1, 98, 4, 0]),
+            Err(CompileError::SelfModifying { ip: 0, addr: 4 })
+        );
+    }
+
+    // Builds the generated source with `rustc` and checks that it produces
+    // the same output as the interpreter, including up to a runtime error.
+    #[test]
+    fn compile_matches_machine() {
+        let programs = [
+            (assemble(COUNTDOWN).unwrap(), vec![]),
+            (
+                assemble(
+                    "
+                          mrb #100
+                          add #ret, #0, [rb+0]
+                          jt #1, #double
+                    ret:  out [x]
+                          end
+                    double: mul [x], #2, [x]
+                          jt #1, [rb+0]
+                    x:    .data 21
+                    ",
+                )
+                .unwrap(),
+                vec![],
+            ),
+            (vec![3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8], vec![8]),
+            (vec![3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8], vec![7]),
+            (vec![104, 5, 4, -1, 99], vec![]),
+        ];
+
+        let mut harness = String::new();
+        for (idx, (code, _)) in programs.iter().enumerate() {
+            harness.push_str(&format!("mod p{} {{\n{}}}\n", idx, compile(code).unwrap()));
+        }
+        harness.push_str(
+            "
+fn main() {
+    let args: Vec<i64> = std::env::args().skip(1).map(|a| a.parse().unwrap()).collect();
+    let input = args[1..].iter().copied();
+    let mut out = Vec::new();
+    let ret = match args[0] {
+",
+        );
+        for idx in 0..programs.len() {
+            harness.push_str(&format!(
+                "        {} => p{}::run(input, |v| out.push(v)),\n",
+                idx, idx
+            ));
+        }
+        harness.push_str(
+            "        _ => unreachable!(),
+    };
+    print!(\"{} {:?}\", ret.is_ok(), out);
+}
+",
+        );
+
+        let dir =
+            TempDir(std::env::temp_dir().join(format!("intcode-compile-{}", std::process::id())));
+        fs::create_dir_all(&dir.0).unwrap();
+        let (src, bin) = (dir.0.join("harness.rs"), dir.0.join("harness"));
+        fs::write(&src, harness).unwrap();
+        let built = match Command::new("rustc")
+            .arg("--edition=2018")
+            .arg("-o")
+            .arg(&bin)
+            .arg(&src)
+            .output()
+        {
+            Ok(built) => built,
+            Err(err) => {
+                eprintln!(
+                    "skipping compile_matches_machine, cannot run rustc: {}",
+                    err
+                );
+                return;
+            }
+        };
+        assert!(
+            built.status.success(),
+            "{}",
+            String::from_utf8_lossy(&built.stderr)
+        );
+
+        for (idx, (code, input)) in programs.iter().enumerate() {
+            let mut output = Vec::new();
+            let ret = Machine::new(code.clone()).run_io(&mut input.iter().copied(), &mut output);
+            let ran = Command::new(&bin)
+                .arg(idx.to_string())
+                .args(input.iter().map(i64::to_string))
+                .output()
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(ran.stdout).unwrap(),
+                format!("{} {:?}", ret.is_ok(), output),
+                "program {}",
+                idx
+            );
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use super::io::{ascii, AsciiOutput, IntcodeOutput};
+use super::{Error, Machine, Status};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::iter;
+
+// Drives a program that talks ASCII: commands go in as lines of text, output
+// comes back as text with any non-ASCII values (usually the puzzle answer)
+// collected separately.
+#[derive(Debug, Clone)]
+pub struct Console {
+    machine: Machine,
+    pending: VecDeque<i64>,
+    output: AsciiOutput,
+}
+
+impl From<Machine> for Console {
+    fn from(machine: Machine) -> Self {
+        Console::new(machine)
+    }
+}
+
+impl Console {
+    pub fn new(machine: Machine) -> Self {
+        Console {
+            machine,
+            pending: VecDeque::new(),
+            output: AsciiOutput::default(),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn into_machine(self) -> Machine {
+        self.machine
+    }
+
+    pub fn send_line(&mut self, line: &str) {
+        self.pending.extend(ascii(line));
+        self.pending.push_back(i64::from(b'\n'));
+    }
+
+    // Runs until the program halts or has consumed every line sent so far.
+    pub fn run(&mut self) -> Result<Status, Error> {
+        let Console {
+            machine,
+            pending,
+            output,
+        } = self;
+        machine.run_io(&mut iter::from_fn(|| pending.pop_front()), output)
+    }
+
+    pub fn command(&mut self, line: &str) -> Result<String, Error> {
+        self.send_line(line);
+        self.run()?;
+        Ok(self.take_text())
+    }
+
+    pub fn text(&self) -> &str {
+        &self.output.text
+    }
+
+    pub fn take_text(&mut self) -> String {
+        std::mem::take(&mut self.output.text)
+    }
+
+    pub fn values(&self) -> &[i64] {
+        &self.output.values
+    }
+
+    pub fn take_values(&mut self) -> Vec<i64> {
+        std::mem::take(&mut self.output.values)
+    }
+
+    // Shows the program's text as it is produced and feeds it lines typed by
+    // the user whenever it waits for input. Non-ASCII values are printed on a
+    // line of their own and also kept in `values`.
+    pub fn interact<R: BufRead, W: Write>(&mut self, mut input: R, out: W) -> io::Result<Status> {
+        let mut echo = Echo {
+            out,
+            values: Vec::new(),
+            error: None,
+        };
+        echo.out.write_all(self.take_text().as_bytes())?;
+        loop {
+            let pending = &mut self.pending;
+            let status = self
+                .machine
+                .run_io(&mut iter::from_fn(|| pending.pop_front()), &mut echo);
+            self.output.values.append(&mut echo.values);
+            if let Some(e) = echo.error.take() {
+                return Err(e);
+            }
+            echo.out.flush()?;
+            match status.map_err(io::Error::other)? {
+                Status::NeedsInput => {}
+                status => return Ok(status),
+            }
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(Status::NeedsInput);
+            }
+            self.send_line(line.trim_end_matches(['\n', '\r']));
+        }
+    }
+}
+
+struct Echo<W> {
+    out: W,
+    values: Vec<i64>,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> IntcodeOutput for Echo<W> {
+    fn push_output(&mut self, value: i64) {
+        if self.error.is_some() {
+            return;
+        }
+        let ret = match value {
+            0..=127 => self.out.write_all(&[value as u8]),
+            _ => {
+                self.values.push(value);
+                writeln!(self.out, "{}", value)
+            }
+        };
+        if let Err(e) = ret.and_then(|_| match value {
+            10 => self.out.flush(),
+            _ => Ok(()),
+        }) {
+            self.error = Some(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::*;
+
+    // Echoes each line after a "> " prompt, then reports its length plus 1000
+    // as a non-ASCII value; an empty line quits.
+    const ECHO: &str = "
+        prompt: out #62
+                out #32
+                add #0, #0, [n]
+        read:   in [c]
+                eq [c], #10, [t]
+                jt [t], #eol
+                add [n], #1, [n]
+                out [c]
+                jt #1, #read
+        eol:    out #10
+                jf [n], #quit
+                add [n], #1000, [t]
+                out [t]
+                jt #1, #prompt
+        quit:   end
+        c:      .data 0
+        n:      .data 0
+        t:      .data 0
+    ";
+
+    fn console() -> Console {
+        Console::new(Machine::new(assemble(ECHO).unwrap()))
+    }
+
+    #[test]
+    fn console_commands() {
+        let mut console = console();
+        assert_eq!(console.run(), Ok(Status::NeedsInput));
+        assert_eq!(console.take_text(), "> ");
+        assert_eq!(console.command("hello").unwrap(), "hello\n> ");
+        assert_eq!(console.command("hi").unwrap(), "hi\n> ");
+        assert_eq!(console.take_values(), &[1005, 1002]);
+        console.send_line("");
+        assert_eq!(console.run(), Ok(Status::Halted));
+        assert_eq!(console.text(), "\n");
+        assert!(console.values().is_empty());
+    }
+
+    #[test]
+    fn console_queues_lines() {
+        let mut console = console();
+        console.send_line("ab");
+        console.send_line("");
+        assert_eq!(console.run(), Ok(Status::Halted));
+        assert_eq!(console.text(), "> ab\n> \n");
+        assert_eq!(console.values(), &[1002]);
+    }
+
+    #[test]
+    fn console_interactive() {
+        let mut out = Vec::new();
+        let mut console = console();
+        assert_eq!(
+            console
+                .interact("abc\r\nxy\n".as_bytes(), &mut out)
+                .unwrap(),
+            Status::NeedsInput
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "> abc\n1003\n> xy\n1002\n> "
+        );
+        assert_eq!(console.values(), &[1003, 1002]);
+
+        let mut out = Vec::new();
+        assert_eq!(
+            console.interact("\n".as_bytes(), &mut out).unwrap(),
+            Status::Halted
+        );
+        assert_eq!(out, b"\n");
+    }
+}
@@ -0,0 +1,289 @@
+use super::cfg::Discovery;
+use super::disasm::{Instruction, Op};
+use super::trace::{TraceEvent, Tracer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+
+// For `jt`/`jf` whether the jump was taken, for `lt`/`eq` whether the
+// comparison held.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl Branch {
+    pub fn outcomes(&self) -> usize {
+        usize::from(self.taken > 0) + usize::from(self.not_taken > 0)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Summary {
+    pub instructions: usize,
+    pub covered: usize,
+    pub branch_outcomes: usize,
+    pub covered_outcomes: usize,
+}
+
+fn percent(n: usize, total: usize) -> f64 {
+    n as f64 * 100.0 / total.max(1) as f64
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} instructions ({:.1}%), {}/{} branch outcomes ({:.1}%)",
+            self.covered,
+            self.instructions,
+            percent(self.covered, self.instructions),
+            self.covered_outcomes,
+            self.branch_outcomes,
+            percent(self.covered_outcomes, self.branch_outcomes)
+        )
+    }
+}
+
+// Collected by running programs with `Machine::run_traced`; the same value can
+// be passed to several runs, or separate ones merged afterwards.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Coverage {
+    hits: BTreeMap<usize, u64>,
+    branches: BTreeMap<usize, Branch>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn hits(&self, addr: usize) -> u64 {
+        self.hits.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub fn executed(&self) -> impl Iterator<Item = usize> + '_ {
+        self.hits.keys().copied()
+    }
+
+    pub fn branch(&self, addr: usize) -> Option<Branch> {
+        self.branches.get(&addr).copied()
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        for (&addr, &n) in &other.hits {
+            *self.hits.entry(addr).or_insert(0) += n;
+        }
+        for (&addr, branch) in &other.branches {
+            let entry = self.branches.entry(addr).or_default();
+            entry.taken += branch.taken;
+            entry.not_taken += branch.not_taken;
+        }
+    }
+
+    // Everything statically reachable in `code` plus any other executed
+    // address that decodes in `code`. Instructions a program wrote for itself
+    // only show up as they were in `code`, or not at all.
+    fn instructions(&self, code: &[i64]) -> BTreeMap<usize, Instruction> {
+        let mut ret = Discovery::new(code).instructions;
+        for &addr in self.hits.keys() {
+            if let Some(inst) = Instruction::decode(code, addr) {
+                ret.entry(addr).or_insert(inst);
+            }
+        }
+        ret
+    }
+
+    pub fn uncovered(&self, code: &[i64]) -> Vec<usize> {
+        self.instructions(code)
+            .into_keys()
+            .filter(|addr| !self.hits.contains_key(addr))
+            .collect()
+    }
+
+    pub fn summary(&self, code: &[i64]) -> Summary {
+        let mut ret = Summary::default();
+        for (addr, inst) in self.instructions(code) {
+            ret.instructions += 1;
+            if self.hits.contains_key(&addr) {
+                ret.covered += 1;
+            }
+            if is_branch(inst.op) {
+                ret.branch_outcomes += 2;
+                ret.covered_outcomes += self.branch(addr).map_or(0, |b| b.outcomes());
+            }
+        }
+        ret
+    }
+
+    // The disassembly of every known instruction, prefixed with how often it
+    // ran and with `#####` where it never did.
+    pub fn write_report<W: Write>(&self, code: &[i64], out: &mut W) -> io::Result<()> {
+        writeln!(out, "coverage: {}", self.summary(code))?;
+        let mut prev = None;
+        for (addr, inst) in self.instructions(code) {
+            if prev.is_some_and(|end| end < addr) {
+                writeln!(out, "{:>8} | {:>5}  ...", "", "")?;
+            }
+            prev = Some(addr + inst.len());
+            let hits = match self.hits(addr) {
+                0 => "#####".to_string(),
+                n => n.to_string(),
+            };
+            write!(out, "{:>8} | {:>5}: {}", hits, addr, inst)?;
+            if is_branch(inst.op) {
+                let branch = self.branch(addr).unwrap_or_default();
+                let (yes, no) = match inst.op {
+                    Op::Jt | Op::Jf => ("taken", "not taken"),
+                    _ => ("true", "false"),
+                };
+                write!(
+                    out,
+                    "  ; {} {}, {} {}",
+                    yes, branch.taken, no, branch.not_taken
+                )?;
+                if branch.outcomes() < 2 {
+                    write!(out, " !")?;
+                }
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_branch(op: Op) -> bool {
+    matches!(op, Op::Jt | Op::Jf | Op::Lt | Op::Eq)
+}
+
+impl Tracer for Coverage {
+    fn trace(&mut self, event: &TraceEvent) {
+        *self.hits.entry(event.addr).or_insert(0) += 1;
+        let ops = &event.operands;
+        let taken = match Op::from_opcode(event.opcode) {
+            Some(Op::Jt) => ops[0] != 0,
+            Some(Op::Jf) => ops[0] == 0,
+            Some(Op::Lt) => ops[0] < ops[1],
+            Some(Op::Eq) => ops[0] == ops[1],
+            _ => return,
+        };
+        let branch = self.branches.entry(event.addr).or_default();
+        if taken {
+            branch.taken += 1;
+        } else {
+            branch.not_taken += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::super::Machine;
+    use super::*;
+
+    // Prints the absolute value of its input, followed by an extra 0 for zero,
+    // so no single input covers every path.
+    const ABS: &str = "
+                in [x]
+                lt [x], #0, [t]
+                jf [t], #print
+                mul [x], #-1, [x]
+        print:  out [x]
+                eq [x], #0, [t]
+                jt [t], #zero
+                end
+        zero:   out #0
+                end
+        x:      .data 0
+        t:      .data 0
+    ";
+
+    fn covered(code: &[i64], input: i64) -> Coverage {
+        let mut coverage = Coverage::new();
+        Machine::new(code.to_vec())
+            .run_traced(&mut Some(input).into_iter(), &mut Vec::new(), &mut coverage)
+            .unwrap();
+        coverage
+    }
+
+    #[test]
+    fn coverage_branches() {
+        let code = assemble(ABS).unwrap();
+        let coverage = covered(&code, 5);
+        assert_eq!(coverage.hits(0), 1);
+        assert_eq!(
+            coverage.branch(2),
+            Some(Branch {
+                taken: 0,
+                not_taken: 1
+            })
+        );
+        assert_eq!(
+            coverage.branch(6),
+            Some(Branch {
+                taken: 1,
+                not_taken: 0
+            })
+        );
+        assert_eq!(coverage.uncovered(&code), &[9, 23, 25]);
+        assert_eq!(
+            coverage.summary(&code),
+            Summary {
+                instructions: 10,
+                covered: 7,
+                branch_outcomes: 8,
+                covered_outcomes: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn coverage_merges_runs() {
+        let code = assemble(ABS).unwrap();
+        let mut coverage = covered(&code, 5);
+        coverage.merge(&covered(&code, -5));
+        coverage.merge(&covered(&code, 0));
+        assert!(coverage.uncovered(&code).is_empty());
+        assert_eq!(coverage.hits(0), 3);
+        assert_eq!(
+            coverage.branch(6),
+            Some(Branch {
+                taken: 2,
+                not_taken: 1
+            })
+        );
+        let summary = coverage.summary(&code);
+        assert_eq!(
+            (summary.covered, summary.covered_outcomes),
+            (summary.instructions, summary.branch_outcomes)
+        );
+    }
+
+    #[test]
+    fn coverage_report() {
+        let code = assemble(ABS).unwrap();
+        let mut report = Vec::new();
+        covered(&code, 5).write_report(&code, &mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report)
+                .unwrap()
+                .lines()
+                .collect::<Vec<_>>(),
+            &[
+                "coverage: 7/10 instructions (70.0%), 4/8 branch outcomes (50.0%)",
+                "       1 |     0: in [26]",
+                "       1 |     2: lt [26], #0, [27]  ; true 0, false 1 !",
+                "       1 |     6: jf [27], #13  ; taken 1, not taken 0 !",
+                "   ##### |     9: mul [26], #-1, [26]",
+                "       1 |    13: out [26]",
+                "       1 |    15: eq [26], #0, [27]  ; true 0, false 1 !",
+                "       1 |    19: jt [27], #23  ; taken 0, not taken 1 !",
+                "       1 |    22: end",
+                "   ##### |    23: out #0",
+                "   ##### |    25: end",
+            ]
+        );
+    }
+}
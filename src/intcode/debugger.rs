@@ -0,0 +1,561 @@
+use super::journal::{DEFAULT_CHECKPOINTS, DEFAULT_INTERVAL};
+use super::{Error, ErrorKind, Machine};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::iter;
+use std::mem;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Stop {
+    Step,
+    Breakpoint(usize),
+    Opcode { addr: usize, opcode: i64 },
+    Watchpoint { addr: usize, old: i64, new: i64 },
+    NeedsInput,
+    Halted,
+    HistoryStart,
+}
+
+#[derive(Debug, Clone)]
+pub struct Debugger {
+    machine: Machine,
+    breakpoints: BTreeSet<usize>,
+    opcode_breakpoints: BTreeSet<i64>,
+    watchpoints: BTreeSet<usize>,
+    output: Vec<i64>,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine) -> Self {
+        Debugger {
+            machine,
+            breakpoints: BTreeSet::new(),
+            opcode_breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn into_machine(self) -> Machine {
+        self.machine
+    }
+
+    pub fn cur(&self) -> usize {
+        self.machine.cur
+    }
+
+    pub fn relative_base(&self) -> i64 {
+        self.machine.relative_base
+    }
+
+    pub fn read(&self, addr: usize) -> Option<i64> {
+        self.machine.code.get(addr)
+    }
+
+    // Stepping back needs the machine's journal, which costs memory for every
+    // step, so it is only kept once asked for.
+    pub fn record_history(&mut self, on: bool) {
+        match (on, self.machine.journal.is_some()) {
+            (true, false) => self
+                .machine
+                .enable_journal(DEFAULT_INTERVAL, DEFAULT_CHECKPOINTS),
+            (false, true) => self.machine.disable_journal(),
+            _ => {}
+        }
+    }
+
+    pub fn records_history(&self) -> bool {
+        self.machine.journal.is_some()
+    }
+
+    pub fn push_input<I: IntoIterator<Item = i64>>(&mut self, input: I) {
+        self.machine.input_queue.extend(input);
+    }
+
+    pub fn output(&self) -> &[i64] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<i64> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn add_breakpoint(&mut self, addr: usize) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: usize) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn add_opcode_breakpoint(&mut self, opcode: i64) -> bool {
+        self.opcode_breakpoints.insert(opcode)
+    }
+
+    pub fn remove_opcode_breakpoint(&mut self, opcode: i64) -> bool {
+        self.opcode_breakpoints.remove(&opcode)
+    }
+
+    pub fn add_watchpoint(&mut self, addr: usize) -> bool {
+        self.watchpoints.insert(addr)
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: usize) -> bool {
+        self.watchpoints.remove(&addr)
+    }
+
+    pub fn step(&mut self) -> Result<Stop, Error> {
+        if self.machine.done {
+            return Ok(Stop::Halted);
+        }
+        // Cells past the memory limit can never be written, so they are not
+        // watched.
+        let watched: Vec<_> = self
+            .watchpoints
+            .iter()
+            .filter_map(|&addr| Some((addr, self.machine.code.get(addr)?)))
+            .collect();
+        let mut input = mem::take(&mut self.machine.input_queue);
+        let ret = self
+            .machine
+            .step_inner(&mut iter::from_fn(|| input.pop_front()), &mut self.output);
+        self.machine.input_queue = input;
+        match ret {
+            Ok(()) => {}
+            Err(ErrorKind::Eof) => return Ok(Stop::NeedsInput),
+            Err(kind) => return Err(self.machine.locate(kind)),
+        }
+        if let Some((addr, old)) = self.machine.last_write {
+            if let Some(new) = self.watched_cell(addr) {
+                return Ok(Stop::Watchpoint { addr, old, new });
+            }
+        }
+        // `last_write` only holds the last cell written, and extension opcodes
+        // may write several, so watched cells are compared too.
+        for (addr, old) in watched {
+            match self.machine.code.get(addr) {
+                Some(new) if new != old => return Ok(Stop::Watchpoint { addr, old, new }),
+                _ => {}
+            }
+        }
+        Ok(if self.machine.done {
+            Stop::Halted
+        } else {
+            Stop::Step
+        })
+    }
+
+    pub fn run(&mut self) -> Result<Stop, Error> {
+        loop {
+            match self.step()? {
+                Stop::Step => {}
+                stop => return Ok(stop),
+            }
+            if let Some(stop) = self.breakpoint_hit() {
+                return Ok(stop);
+            }
+        }
+    }
+
+    // Undoes the last step, dropping any output it produced; input it consumed
+    // is queued again. Which cells have been executed is rolled back, code
+    // writes already reported by memory protection are not. Without history
+    // this is always at the start of it.
+    pub fn step_back(&mut self) -> Stop {
+        match self.machine.step_back() {
+            Some(undone) => {
+                let len = self.output.len().saturating_sub(undone.outputs);
+                self.output.truncate(len);
+                Stop::Step
+            }
+            None => Stop::HistoryStart,
+        }
+    }
+
+    pub fn run_back(&mut self) -> Stop {
+        loop {
+            match self.step_back() {
+                Stop::Step => {}
+                stop => return stop,
+            }
+            if let Some(stop) = self.breakpoint_hit() {
+                return stop;
+            }
+        }
+    }
+
+    fn watched_cell(&self, addr: usize) -> Option<i64> {
+        self.machine
+            .code
+            .get(addr)
+            .filter(|_| self.watchpoints.contains(&addr))
+    }
+
+    fn breakpoint_hit(&self) -> Option<Stop> {
+        let addr = self.machine.cur;
+        if self.breakpoints.contains(&addr) {
+            return Some(Stop::Breakpoint(addr));
+        }
+        self.machine
+            .code
+            .get(addr)
+            .map(|word| word % 100)
+            .filter(|opcode| self.opcode_breakpoints.contains(opcode))
+            .map(|opcode| Stop::Opcode { addr, opcode })
+    }
+}
+
+const HELP: &str = "\
+commands:
+  s [n]         step n instructions (default 1)
+  c             continue until a breakpoint, input request or halt
+  sb [n]        step back n instructions (default 1)
+  rc            run backwards until a breakpoint or the start of history
+  hist          toggle recording the history needed to step back
+  b <addr>      toggle breakpoint at address
+  bo <opcode>   toggle breakpoint on opcode
+  w <addr>      toggle watchpoint on writes to address
+  i <v>...      queue input values
+  p <addr> [n]  print n memory cells (default 1)
+  r             show registers
+  l [n]         list n instructions around the cursor (default 5)
+  q             quit";
+
+fn report<W: Write>(out: &mut W, result: Result<Stop, Error>) -> io::Result<bool> {
+    match result {
+        Ok(Stop::Step) => Ok(true),
+        Ok(Stop::Breakpoint(addr)) => writeln!(out, "breakpoint at {}", addr).map(|_| false),
+        Ok(Stop::Opcode { addr, opcode }) => {
+            writeln!(out, "opcode {} breakpoint at {}", opcode, addr).map(|_| false)
+        }
+        Ok(Stop::Watchpoint { addr, old, new }) => {
+            writeln!(out, "watchpoint {}: {} -> {}", addr, old, new).map(|_| false)
+        }
+        Ok(Stop::NeedsInput) => writeln!(out, "waiting for input").map(|_| false),
+        Ok(Stop::Halted) => writeln!(out, "halted").map(|_| false),
+        Ok(Stop::HistoryStart) => writeln!(out, "at start of history").map(|_| false),
+        Err(e) => writeln!(out, "error: {}", e).map(|_| false),
+    }
+}
+
+fn list<W: Write>(out: &mut W, debugger: &Debugger, count: usize) -> io::Result<()> {
+    let listing = debugger.machine.disassemble();
+    let lines = listing.lines();
+    let pos = lines
+        .iter()
+        .position(|l| l.addr == debugger.machine.cur)
+        .unwrap_or(0);
+    for line in lines
+        .iter()
+        .skip(pos.saturating_sub(count))
+        .take(count * 2 + 1)
+    {
+        let marker = if line.addr == debugger.machine.cur {
+            '>'
+        } else {
+            ' '
+        };
+        writeln!(out, "{}{:>5}: {}", marker, line.addr, line.item)?;
+    }
+    Ok(())
+}
+
+pub fn repl<R: BufRead, W: Write>(debugger: &mut Debugger, input: R, mut out: W) -> io::Result<()> {
+    write!(out, "(icdb) ")?;
+    out.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let cmd = words.next().unwrap_or("");
+        let args: Result<Vec<i64>, _> = words.map(str::parse).collect();
+        let args = match args {
+            Ok(args) => args,
+            Err(_) => {
+                write!(out, "invalid argument\n(icdb) ")?;
+                out.flush()?;
+                continue;
+            }
+        };
+        let arg = |idx: usize, default: i64| args.get(idx).copied().unwrap_or(default);
+        let toggle = |added: bool| if added { "set" } else { "cleared" };
+        let output_len = debugger.output.len();
+        match cmd {
+            "" => {}
+            "s" | "step" => {
+                for _ in 0..arg(0, 1) {
+                    if !report(&mut out, debugger.step())? {
+                        break;
+                    }
+                }
+            }
+            "c" | "continue" => {
+                report(&mut out, debugger.run())?;
+            }
+            "sb" | "back" | "rc" if !debugger.records_history() => {
+                writeln!(out, "history is not recorded, enable it with `hist`")?;
+            }
+            "sb" | "back" => {
+                for _ in 0..arg(0, 1) {
+                    if !report(&mut out, Ok(debugger.step_back()))? {
+                        break;
+                    }
+                }
+            }
+            "rc" => {
+                report(&mut out, Ok(debugger.run_back()))?;
+            }
+            "b" | "bo" | "w" if args.len() != 1 || args[0] < 0 => {
+                writeln!(out, "expected one non-negative argument")?;
+            }
+            "b" => {
+                let addr = args[0] as usize;
+                let added = debugger.add_breakpoint(addr) || !debugger.remove_breakpoint(addr);
+                writeln!(out, "breakpoint {} at {}", toggle(added), addr)?;
+            }
+            "bo" => {
+                let opcode = args[0];
+                let added = debugger.add_opcode_breakpoint(opcode)
+                    || !debugger.remove_opcode_breakpoint(opcode);
+                writeln!(out, "opcode breakpoint {} on {}", toggle(added), opcode)?;
+            }
+            "w" => {
+                let addr = args[0] as usize;
+                let added = debugger.add_watchpoint(addr) || !debugger.remove_watchpoint(addr);
+                writeln!(out, "watchpoint {} at {}", toggle(added), addr)?;
+            }
+            "hist" => {
+                debugger.record_history(!debugger.records_history());
+                let state = if debugger.records_history() {
+                    "on"
+                } else {
+                    "off"
+                };
+                writeln!(out, "history recording {}", state)?;
+            }
+            "i" | "input" => debugger.push_input(args.iter().copied()),
+            "p" | "print" => {
+                let start = arg(0, 0).max(0) as usize;
+                for addr in start..start + arg(1, 1).max(0) as usize {
+                    match debugger.read(addr) {
+                        Some(val) => writeln!(out, "{:>5}: {}", addr, val)?,
+                        None => {
+                            writeln!(out, "{:>5}: out of bounds", addr)?;
+                            break;
+                        }
+                    }
+                }
+            }
+            "r" | "regs" => writeln!(
+                out,
+                "cur = {}, relative_base = {}, done = {}",
+                debugger.cur(),
+                debugger.relative_base(),
+                debugger.machine.done
+            )?,
+            "l" | "list" => list(&mut out, debugger, arg(0, 5).max(0) as usize)?,
+            "h" | "help" => writeln!(out, "{}", HELP)?,
+            "q" | "quit" => return Ok(()),
+            _ => writeln!(out, "unknown command `{}`, try `h`", cmd)?,
+        }
+        if debugger.output.len() > output_len {
+            let new: Vec<_> = debugger.output[output_len..]
+                .iter()
+                .map(i64::to_string)
+                .collect();
+            writeln!(out, "output: {}", new.join(" "))?;
+        }
+        write!(out, "(icdb) ")?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::super::disasm::Param;
+    use super::super::extension::{Action, Registry};
+    use super::super::fixtures;
+    use super::*;
+
+    fn debugger(src: &str) -> Debugger {
+        Debugger::new(Machine::new(assemble(src).unwrap()))
+    }
+
+    fn countdown() -> Debugger {
+        Debugger::new(Machine::new(fixtures::countdown(2)))
+    }
+
+    #[test]
+    fn debugger_single_step() {
+        let mut dbg = countdown();
+        assert_eq!(dbg.step().unwrap(), Stop::Step);
+        assert_eq!(dbg.cur(), 2);
+        assert_eq!(dbg.output(), &[2]);
+        assert_eq!(dbg.step().unwrap(), Stop::Step);
+        assert_eq!(dbg.read(10), Some(1));
+    }
+
+    #[test]
+    fn debugger_breakpoints() {
+        let mut dbg = countdown();
+        dbg.add_breakpoint(6);
+        assert_eq!(dbg.run().unwrap(), Stop::Breakpoint(6));
+        assert_eq!(dbg.run().unwrap(), Stop::Breakpoint(6));
+        assert_eq!(dbg.read(10), Some(0));
+        dbg.remove_breakpoint(6);
+        dbg.add_opcode_breakpoint(99);
+        assert_eq!(
+            dbg.run().unwrap(),
+            Stop::Opcode {
+                addr: 9,
+                opcode: 99
+            }
+        );
+        assert_eq!(dbg.run().unwrap(), Stop::Halted);
+        assert_eq!(dbg.output(), &[2, 1]);
+    }
+
+    #[test]
+    fn debugger_watchpoint_and_input() {
+        let mut dbg = debugger("in [x]\nmul [x], #3, [x]\nend\nx: .data 0");
+        dbg.add_watchpoint(7);
+        assert_eq!(dbg.run().unwrap(), Stop::NeedsInput);
+        dbg.push_input(vec![4]);
+        assert_eq!(
+            dbg.run().unwrap(),
+            Stop::Watchpoint {
+                addr: 7,
+                old: 0,
+                new: 4
+            }
+        );
+        assert_eq!(
+            dbg.run().unwrap(),
+            Stop::Watchpoint {
+                addr: 7,
+                old: 4,
+                new: 12
+            }
+        );
+        assert_eq!(dbg.relative_base(), 0);
+    }
+
+    #[test]
+    fn debugger_watchpoint_sees_every_write() {
+        let mut registry = Registry::new();
+        registry
+            .register(22, "swap", &[Param::Addr, Param::Addr], |call| {
+                let (a, b) = (call.arg(0), call.arg(1));
+                let (x, y) = (call.read(a)?, call.read(b)?);
+                call.write(a, y)?;
+                call.write(b, x).map(|_| Action::Continue)
+            })
+            .unwrap();
+        let mut machine = Machine::new(vec![22, 4, 5, 99, 1, 2]);
+        machine.set_extensions(registry);
+        let mut dbg = Debugger::new(machine);
+        dbg.add_watchpoint(4);
+        assert_eq!(
+            dbg.run().unwrap(),
+            Stop::Watchpoint {
+                addr: 4,
+                old: 1,
+                new: 2
+            }
+        );
+    }
+
+    #[test]
+    fn debugger_watchpoint_past_memory_limit() {
+        let mut dbg = Debugger::new(Machine::with_memory_limit(vec![1101, 1, 2, 3, 99], 8));
+        dbg.add_watchpoint(100);
+        dbg.add_opcode_breakpoint(1);
+        assert_eq!(dbg.run().unwrap(), Stop::Halted);
+        dbg.machine.cur = 100;
+        assert_eq!(dbg.breakpoint_hit(), None);
+    }
+
+    #[test]
+    fn debugger_repl_session() {
+        let mut dbg = countdown();
+        let mut out = Vec::new();
+        repl(&mut dbg, "b 6\nc\nr\nc\nb 6\nc\nq\n".as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out.replace("(icdb) ", "").lines().collect::<Vec<_>>(),
+            &[
+                "breakpoint set at 6",
+                "breakpoint at 6",
+                "output: 2",
+                "cur = 6, relative_base = 0, done = false",
+                "breakpoint at 6",
+                "output: 1",
+                "breakpoint cleared at 6",
+                "halted",
+            ]
+        );
+    }
+
+    #[test]
+    fn debugger_steps_back() {
+        let mut dbg = debugger("in [x]\nmul [x], #3, [x]\nout [x]\nend\nx: .data 0");
+        dbg.record_history(true);
+        dbg.push_input(vec![4]);
+        dbg.add_breakpoint(2);
+        assert_eq!(dbg.run().unwrap(), Stop::Breakpoint(2));
+        assert_eq!(dbg.run().unwrap(), Stop::Halted);
+        assert_eq!(dbg.output(), &[12]);
+        assert_eq!(dbg.run_back(), Stop::Breakpoint(2));
+        assert_eq!((dbg.read(9), dbg.output()), (Some(4), &[][..]));
+        assert_eq!(dbg.step_back(), Stop::Step);
+        assert_eq!(dbg.step_back(), Stop::HistoryStart);
+        assert_eq!((dbg.cur(), dbg.read(9)), (0, Some(0)));
+        assert_eq!(dbg.run().unwrap(), Stop::Breakpoint(2));
+        assert_eq!(dbg.run().unwrap(), Stop::Halted);
+        assert_eq!(dbg.output(), &[12]);
+    }
+
+    #[test]
+    fn debugger_history_is_opt_in() {
+        let mut dbg = countdown();
+        assert!(!dbg.records_history());
+        assert_eq!(dbg.step().unwrap(), Stop::Step);
+        assert_eq!(dbg.step_back(), Stop::HistoryStart);
+        assert_eq!(dbg.cur(), 2);
+        dbg.record_history(true);
+        assert_eq!(dbg.step().unwrap(), Stop::Step);
+        assert_eq!(dbg.step_back(), Stop::Step);
+        assert_eq!(dbg.cur(), 2);
+        dbg.record_history(false);
+        assert!(dbg.machine().journal().is_none());
+    }
+
+    #[test]
+    fn debugger_repl_steps_back() {
+        let mut dbg = countdown();
+        let mut out = Vec::new();
+        repl(
+            &mut dbg,
+            "sb\nhist\ns 4\nsb 2\nr\nrc\nq\n".as_bytes(),
+            &mut out,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out.replace("(icdb) ", "").lines().collect::<Vec<_>>(),
+            &[
+                "history is not recorded, enable it with `hist`",
+                "history recording on",
+                "output: 2 1",
+                "cur = 6, relative_base = 0, done = false",
+                "at start of history",
+            ]
+        );
+        assert_eq!(dbg.output(), &[] as &[i64]);
+    }
+}
@@ -0,0 +1,443 @@
+use super::{
+    arg_mode, Machine, OP_ADD, OP_END, OP_EQ, OP_IN, OP_JF, OP_JT, OP_LT, OP_MRB, OP_MUL, OP_OUT,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+pub(super) const MAX_INSTRUCTION_LEN: usize = 4;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Op {
+    Add,
+    Mul,
+    In,
+    Out,
+    Jt,
+    Jf,
+    Lt,
+    Eq,
+    Mrb,
+    End,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Param {
+    Val,
+    Addr,
+}
+
+const OPS: [Op; 10] = [
+    Op::Add,
+    Op::Mul,
+    Op::In,
+    Op::Out,
+    Op::Jt,
+    Op::Jf,
+    Op::Lt,
+    Op::Eq,
+    Op::Mrb,
+    Op::End,
+];
+
+impl Op {
+    pub fn from_opcode(opcode: i64) -> Option<Op> {
+        OPS.iter().copied().find(|op| op.opcode() == opcode)
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Op> {
+        OPS.iter().copied().find(|op| op.mnemonic() == mnemonic)
+    }
+
+    pub fn opcode(self) -> i64 {
+        match self {
+            Op::Add => OP_ADD,
+            Op::Mul => OP_MUL,
+            Op::In => OP_IN,
+            Op::Out => OP_OUT,
+            Op::Jt => OP_JT,
+            Op::Jf => OP_JF,
+            Op::Lt => OP_LT,
+            Op::Eq => OP_EQ,
+            Op::Mrb => OP_MRB,
+            Op::End => OP_END,
+        }
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Op::Add => "add",
+            Op::Mul => "mul",
+            Op::In => "in",
+            Op::Out => "out",
+            Op::Jt => "jt",
+            Op::Jf => "jf",
+            Op::Lt => "lt",
+            Op::Eq => "eq",
+            Op::Mrb => "mrb",
+            Op::End => "end",
+        }
+    }
+
+    pub fn params(self) -> &'static [Param] {
+        use Param::*;
+        match self {
+            Op::Add | Op::Mul | Op::Lt | Op::Eq => &[Val, Val, Addr],
+            Op::In => &[Addr],
+            Op::Out | Op::Mrb => &[Val],
+            Op::Jt | Op::Jf => &[Val, Val],
+            Op::End => &[],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Mode {
+    Position,
+    Immediate,
+    Relative,
+}
+
+impl Mode {
+    pub fn from_digit(digit: i64) -> Option<Mode> {
+        match digit {
+            0 => Some(Mode::Position),
+            1 => Some(Mode::Immediate),
+            2 => Some(Mode::Relative),
+            _ => None,
+        }
+    }
+
+    pub fn digit(self) -> i64 {
+        match self {
+            Mode::Position => 0,
+            Mode::Immediate => 1,
+            Mode::Relative => 2,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Operand {
+    pub mode: Mode,
+    pub value: i64,
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mode {
+            Mode::Position => write!(f, "[{}]", self.value),
+            Mode::Immediate => write!(f, "#{}", self.value),
+            Mode::Relative if self.value < 0 => write!(f, "[rb-{}]", -(self.value as i128)),
+            Mode::Relative => write!(f, "[rb+{}]", self.value),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Instruction {
+    pub op: Op,
+    args: [Operand; 3],
+}
+
+impl Instruction {
+    pub fn new(op: Op, operands: &[Operand]) -> Option<Self> {
+        let params = op.params();
+        if operands.len() != params.len()
+            || params
+                .iter()
+                .zip(operands)
+                .any(|(&p, a)| p == Param::Addr && a.mode == Mode::Immediate)
+        {
+            return None;
+        }
+        let mut args = [Operand {
+            mode: Mode::Immediate,
+            value: 0,
+        }; 3];
+        args[..operands.len()].copy_from_slice(operands);
+        Some(Instruction { op, args })
+    }
+
+    pub fn decode(code: &[i64], addr: usize) -> Option<Self> {
+        let word = *code.get(addr)?;
+        if word < 0 {
+            return None;
+        }
+        let op = Op::from_opcode(word % 100)?;
+        let arity = op.params().len();
+        if word / 10_i64.pow(2 + arity as u32) != 0 {
+            return None;
+        }
+        let raw = code.get(addr + 1..addr + 1 + arity)?;
+        let mut operands = [Operand {
+            mode: Mode::Immediate,
+            value: 0,
+        }; 3];
+        for (idx, (operand, &value)) in operands.iter_mut().zip(raw).enumerate() {
+            *operand = Operand {
+                mode: Mode::from_digit(arg_mode(word, idx))?,
+                value,
+            };
+        }
+        Self::new(op, &operands[..arity])
+    }
+
+    pub fn operands(&self) -> &[Operand] {
+        &self.args[..self.op.params().len()]
+    }
+
+    pub fn word(&self) -> i64 {
+        self.operands()
+            .iter()
+            .enumerate()
+            .fold(self.op.opcode(), |word, (idx, a)| {
+                word + a.mode.digit() * 10_i64.pow(2 + idx as u32)
+            })
+    }
+
+    pub fn encode(&self) -> Vec<i64> {
+        let mut ret = vec![self.word()];
+        ret.extend(self.operands().iter().map(|a| a.value));
+        ret
+    }
+
+    pub fn len(&self) -> usize {
+        1 + self.op.params().len()
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.op.mnemonic())?;
+        for (idx, a) in self.operands().iter().enumerate() {
+            write!(f, "{}{}", if idx == 0 { " " } else { ", " }, a)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Item {
+    Instruction(Instruction),
+    Data(i64),
+}
+
+impl Item {
+    pub fn len(&self) -> usize {
+        match self {
+            Item::Instruction(instr) => instr.len(),
+            Item::Data(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Item::Instruction(instr) => write!(f, "{}", instr),
+            Item::Data(n) => write!(f, ".data {}", n),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Line {
+    pub addr: usize,
+    pub item: Item,
+}
+
+#[derive(Debug, Clone)]
+pub struct Listing {
+    lines: Vec<Line>,
+    cursor: Option<usize>,
+    notes: BTreeMap<usize, Vec<String>>,
+}
+
+impl Listing {
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    pub fn line_at(&self, addr: usize) -> Option<&Line> {
+        self.lines
+            .binary_search_by_key(&addr, |l| l.addr)
+            .ok()
+            .map(|i| &self.lines[i])
+    }
+
+    pub fn cursor(&self) -> Option<usize> {
+        self.cursor
+    }
+
+    pub fn annotate<S: Into<String>>(&mut self, addr: usize, note: S) {
+        self.notes.entry(addr).or_default().push(note.into());
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut notes = self.notes.iter().peekable();
+        for line in &self.lines {
+            while let Some((_, text)) = notes.next_if(|(&addr, _)| addr <= line.addr) {
+                for note in text {
+                    writeln!(f, "       ; {}", note)?;
+                }
+            }
+            write!(f, "{:>5}: {}", line.addr, line.item)?;
+            if Some(line.addr) == self.cursor {
+                write!(f, "  ; <- cur")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn disassemble(code: &[i64]) -> Listing {
+    let mut lines = Vec::new();
+    disassemble_into(code, 0, code.len(), None, &mut lines);
+    Listing {
+        lines,
+        cursor: None,
+        notes: BTreeMap::new(),
+    }
+}
+
+// Lists the first `len` cells of `code`, which starts at address `base`; the
+// cells after them are only read as operands. Instructions that would
+// straddle the cursor are emitted as data so that the listing always has a
+// boundary at the instruction about to execute.
+fn disassemble_into(
+    code: &[i64],
+    base: usize,
+    len: usize,
+    cursor: Option<usize>,
+    lines: &mut Vec<Line>,
+) {
+    let mut offset = 0;
+    while offset < len {
+        let addr = base + offset;
+        let item = match Instruction::decode(code, offset) {
+            Some(instr) if cursor.is_none_or(|c| c <= addr || c >= addr + instr.len()) => {
+                Item::Instruction(instr)
+            }
+            _ => Item::Data(code[offset]),
+        };
+        lines.push(Line { addr, item });
+        offset += item.len();
+    }
+}
+
+impl Machine {
+    // Only the allocated pages and the cell at `cur` are listed, so a program
+    // that wrote far out in memory doesn't list every zero in between.
+    pub fn disassemble(&self) -> Listing {
+        let mut ranges: Vec<Range<usize>> = self.code.allocated().collect();
+        ranges.push(self.cur..self.cur + 1);
+        ranges.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<usize>> = Vec::new();
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        let mut lines = Vec::new();
+        for range in merged {
+            let end = (range.end + MAX_INSTRUCTION_LEN - 1)
+                .min(self.code.len())
+                .max(range.end);
+            let code: Vec<i64> = (range.start..end).map(|addr| self.code[addr]).collect();
+            disassemble_into(&code, range.start, range.len(), Some(self.cur), &mut lines);
+        }
+        Listing {
+            lines,
+            cursor: Some(self.cur),
+            notes: BTreeMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::iter;
+
+    #[test]
+    fn disasm_modes() {
+        assert_eq!(
+            disassemble(&[21101, 3, -5, 7, 204, -2, 99])
+                .to_string()
+                .lines()
+                .collect::<Vec<_>>(),
+            &[
+                "    0: add #3, #-5, [rb+7]",
+                "    4: out [rb-2]",
+                "    6: end",
+            ]
+        );
+    }
+
+    #[test]
+    fn disasm_data_fallback() {
+        let listing = disassemble(&[11101, 99, 5, -1]);
+        assert_eq!(
+            listing.lines().iter().map(|l| l.item).collect::<Vec<_>>(),
+            &[
+                Item::Data(11101),
+                Item::Instruction(Instruction::new(Op::End, &[]).unwrap()),
+                Item::Data(5),
+                Item::Data(-1),
+            ]
+        );
+    }
+
+    #[test]
+    fn disasm_encode_round_trip() {
+        let code = [1002, 4, 3, 4, 2101, -7, 8, 9];
+        let encoded: Vec<i64> = disassemble(&code)
+            .lines()
+            .iter()
+            .flat_map(|l| match l.item {
+                Item::Instruction(instr) => instr.encode(),
+                Item::Data(n) => vec![n],
+            })
+            .collect();
+        assert_eq!(encoded, code);
+    }
+
+    #[test]
+    fn disasm_live_snapshot() {
+        let mut machine = Machine::new(vec![1002, 4, 3, 4, 33]);
+        assert_eq!(
+            machine.disassemble().line_at(4).unwrap().item,
+            Item::Data(33)
+        );
+        machine.run_to_end(iter::empty()).unwrap();
+        let listing = machine.disassemble();
+        assert_eq!(listing.cursor(), Some(4));
+        assert_eq!(listing.line_at(4).unwrap().item.to_string(), "end");
+    }
+
+    #[test]
+    fn disasm_cursor_realigns() {
+        let mut machine = Machine::new(vec![1101, 104, 7, 0, 99]);
+        machine.cur = 1;
+        let listing = machine.disassemble();
+        assert_eq!(listing.line_at(0).unwrap().item, Item::Data(1101));
+        assert_eq!(listing.line_at(1).unwrap().item.to_string(), "out #7");
+    }
+
+    #[test]
+    fn disasm_skips_unallocated_memory() {
+        let far = 5_000_000;
+        let mut machine = Machine::new(vec![1101, 7, 0, far, 99]);
+        machine.run_to_end(iter::empty()).unwrap();
+        let listing = machine.disassemble();
+        assert!(listing.lines().len() < 3000);
+        assert_eq!(listing.line_at(4).unwrap().item.to_string(), "end");
+        assert_eq!(listing.line_at(far as usize).unwrap().item, Item::Data(7));
+        assert_eq!(listing.lines().last().unwrap().addr, far as usize);
+    }
+}
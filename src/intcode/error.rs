@@ -0,0 +1,158 @@
+use super::protect::Access;
+use std::fmt;
+
+const TRAIL_LEN: usize = 16;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ErrorKind {
+    UnknownOpcode { opcode: i64 },
+    UnknownOpmode { mode: i64 },
+    InvalidOpmode { mode: i64 },
+    OutOfBounds { addr: i64 },
+    Eof,
+    NoTermination,
+    InstructionLimit { executed: u64 },
+    DeadlineExceeded { executed: u64 },
+    Overflow,
+    Unrepresentable { value: String },
+    Protected { addr: i64, access: Access },
+    ModifiedCode { addr: usize },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub ip: usize,
+    pub instruction: i64,
+    pub relative_base: i64,
+    pub backtrace: Vec<usize>,
+}
+
+impl Error {
+    pub fn address(&self) -> Option<i64> {
+        match self.kind {
+            ErrorKind::OutOfBounds { addr } | ErrorKind::Protected { addr, .. } => Some(addr),
+            ErrorKind::ModifiedCode { addr } => Some(addr as i64),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::UnknownOpcode { opcode } => write!(f, "unknown opcode {}", opcode),
+            ErrorKind::UnknownOpmode { mode } => write!(f, "unknown parameter mode {}", mode),
+            ErrorKind::InvalidOpmode { mode } => {
+                write!(f, "parameter mode {} is not valid for a destination", mode)
+            }
+            ErrorKind::OutOfBounds { addr } => write!(f, "address {} is out of bounds", addr),
+            ErrorKind::Eof => write!(f, "input exhausted"),
+            ErrorKind::NoTermination => write!(f, "execution ran past the end of the program"),
+            ErrorKind::InstructionLimit { executed } => {
+                write!(
+                    f,
+                    "instruction limit reached after {} instructions",
+                    executed
+                )
+            }
+            ErrorKind::DeadlineExceeded { executed } => {
+                write!(f, "deadline exceeded after {} instructions", executed)
+            }
+            ErrorKind::Overflow => write!(f, "arithmetic overflow"),
+            ErrorKind::Unrepresentable { value } => {
+                write!(f, "value {} does not fit in 64 bits", value)
+            }
+            ErrorKind::Protected { addr, access } => {
+                write!(f, "{} access to protected address {}", access, addr)
+            }
+            ErrorKind::ModifiedCode { addr } => {
+                write!(f, "write to already executed code at address {}", addr)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at address {} (instruction {}, relative base {})",
+            self.kind, self.ip, self.instruction, self.relative_base
+        )?;
+        if !self.backtrace.is_empty() {
+            let trail: Vec<_> = self.backtrace.iter().map(usize::to_string).collect();
+            write!(f, "\n  recently executed: {}", trail.join(" -> "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct Trail {
+    addrs: [usize; TRAIL_LEN],
+    len: usize,
+    head: usize,
+}
+
+impl Trail {
+    pub(super) fn push(&mut self, addr: usize) {
+        self.addrs[self.head] = addr;
+        self.head = (self.head + 1) % TRAIL_LEN;
+        self.len = (self.len + 1).min(TRAIL_LEN);
+    }
+
+    pub(super) fn to_vec(&self) -> Vec<usize> {
+        (0..self.len)
+            .map(|i| self.addrs[(self.head + TRAIL_LEN - self.len + i) % TRAIL_LEN])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Machine;
+    use super::*;
+    use std::iter;
+
+    #[test]
+    fn error_trail_wraps() {
+        let mut trail = Trail::default();
+        for addr in 0..TRAIL_LEN + 3 {
+            trail.push(addr);
+        }
+        assert_eq!(trail.to_vec(), (3..TRAIL_LEN + 3).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn error_located_out_of_bounds() {
+        let err = Machine::new(vec![1101, 1, 1, 9, 104, 0, 4, -3, 99, 0])
+            .run_to_end(iter::empty())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            Error {
+                kind: ErrorKind::OutOfBounds { addr: -3 },
+                ip: 6,
+                instruction: 4,
+                relative_base: 0,
+                backtrace: vec![0, 4],
+            }
+        );
+        assert_eq!(err.address(), Some(-3));
+    }
+
+    #[test]
+    fn error_display() {
+        let err = Machine::new(vec![109, 5, 11101, 1, 2, 3, 99])
+            .run_to_end(iter::empty())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "parameter mode 1 is not valid for a destination at address 2 \
+             (instruction 11101, relative base 5)\n  recently executed: 0"
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use super::cell::Cell;
+use super::disasm::{Op, Param};
+use super::io::{IntcodeInput, IntcodeOutput};
+use super::{ErrorKind, Machine};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+pub const MAX_PARAMS: usize = 8;
+
+type Handler<C> = dyn Fn(&mut Call<C>) -> Result<Action, ErrorKind> + Send + Sync;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Action {
+    Continue,
+    Jump(i64),
+    Halt(i64),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RegisterError {
+    InvalidOpcode(i64),
+    Builtin(i64),
+    Duplicate(i64),
+    TooManyParams(usize),
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegisterError::InvalidOpcode(opcode) => {
+                write!(f, "opcode {} is not in the range 1..=99", opcode)
+            }
+            RegisterError::Builtin(opcode) => write!(f, "opcode {} is built in", opcode),
+            RegisterError::Duplicate(opcode) => {
+                write!(f, "opcode {} is already registered", opcode)
+            }
+            RegisterError::TooManyParams(n) => write!(
+                f,
+                "{} parameters requested, at most {} are supported",
+                n, MAX_PARAMS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RegisterError {}
+
+pub struct Extension<C = i64> {
+    pub opcode: i64,
+    pub name: String,
+    pub params: Vec<Param>,
+    handler: Box<Handler<C>>,
+}
+
+impl<C> fmt::Debug for Extension<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Extension")
+            .field("opcode", &self.opcode)
+            .field("name", &self.name)
+            .field("params", &self.params)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct Registry<C = i64> {
+    extensions: BTreeMap<i64, Arc<Extension<C>>>,
+}
+
+impl<C> Default for Registry<C> {
+    fn default() -> Self {
+        Registry {
+            extensions: BTreeMap::new(),
+        }
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<C: Cell> Registry<C> {
+    pub fn register<F>(
+        &mut self,
+        opcode: i64,
+        name: &str,
+        params: &[Param],
+        handler: F,
+    ) -> Result<(), RegisterError>
+    where
+        F: Fn(&mut Call<C>) -> Result<Action, ErrorKind> + Send + Sync + 'static,
+    {
+        if !(1..=99).contains(&opcode) {
+            return Err(RegisterError::InvalidOpcode(opcode));
+        }
+        if Op::from_opcode(opcode).is_some() {
+            return Err(RegisterError::Builtin(opcode));
+        }
+        if self.extensions.contains_key(&opcode) {
+            return Err(RegisterError::Duplicate(opcode));
+        }
+        if params.len() > MAX_PARAMS {
+            return Err(RegisterError::TooManyParams(params.len()));
+        }
+        let extension = Extension {
+            opcode,
+            name: name.to_string(),
+            params: params.to_vec(),
+            handler: Box::new(handler),
+        };
+        self.extensions.insert(opcode, Arc::new(extension));
+        Ok(())
+    }
+
+    pub fn get(&self, opcode: i64) -> Option<&Extension<C>> {
+        self.extensions.get(&opcode).map(|ext| &**ext)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Extension<C>> {
+        self.extensions.values().map(|ext| &**ext)
+    }
+}
+
+// What an extension handler sees: its operands decoded according to its
+// parameter kinds (values for `Val`, addresses for `Addr`) and access to the
+// machine's memory and I/O.
+pub struct Call<'a, C = i64> {
+    args: Vec<C>,
+    machine: &'a mut Machine<C>,
+    input: &'a mut dyn IntcodeInput<C>,
+    output: &'a mut dyn IntcodeOutput<C>,
+}
+
+impl<C: Cell> Call<'_, C> {
+    pub fn arg(&self, idx: usize) -> C {
+        self.args[idx].clone()
+    }
+
+    pub fn args(&self) -> &[C] {
+        &self.args
+    }
+
+    pub fn relative_base(&self) -> i64 {
+        self.machine.relative_base
+    }
+
+    pub fn read(&self, addr: i64) -> Result<C, ErrorKind> {
+        self.machine.get(addr)
+    }
+
+    pub fn write(&mut self, addr: i64, val: C) -> Result<(), ErrorKind> {
+        self.machine.set(addr, val).map(|_| ())
+    }
+
+    // Returns `ErrorKind::Eof` when no input is available; the whole instruction
+    // is then retried once more input arrives, so read at most one value and do
+    // so before any other side effect.
+    pub fn input(&mut self) -> Result<C, ErrorKind> {
+        let val = self.input.next_input().ok_or(ErrorKind::Eof)?;
+        if let Some(journal) = &mut self.machine.journal {
+            journal.record_input(&val);
+        }
+        Ok(val)
+    }
+
+    pub fn output(&mut self, val: C) {
+        if let Some(journal) = &mut self.machine.journal {
+            journal.record_output();
+        }
+        self.output.push_output(val);
+    }
+}
+
+impl<C: Cell> Machine<C> {
+    pub fn set_extensions<R: Into<Arc<Registry<C>>>>(&mut self, registry: R) {
+        self.extensions = Some(registry.into());
+    }
+
+    pub fn extensions(&self) -> Option<&Registry<C>> {
+        self.extensions.as_deref()
+    }
+
+    pub fn exit_code(&self) -> Option<i64> {
+        self.exit_code
+    }
+
+    pub(super) fn run_extension(
+        &mut self,
+        opcode: i64,
+        input: &mut impl IntcodeInput<C>,
+        output: &mut impl IntcodeOutput<C>,
+    ) -> Result<usize, ErrorKind> {
+        let registry = match &self.extensions {
+            Some(registry) if registry.extensions.contains_key(&opcode) => Arc::clone(registry),
+            _ => return Err(ErrorKind::UnknownOpcode { opcode }),
+        };
+        let ext = &registry.extensions[&opcode];
+        let args = ext
+            .params
+            .iter()
+            .enumerate()
+            .map(|(idx, param)| match param {
+                Param::Val => self.get_val_arg(idx),
+                Param::Addr => self.get_addr_arg(idx).map(C::from),
+            })
+            .collect::<Result<_, _>>()?;
+        let mut call = Call {
+            args,
+            machine: self,
+            input,
+            output,
+        };
+        match (ext.handler)(&mut call)? {
+            Action::Continue => self.inc(1 + ext.params.len()),
+            Action::Jump(dest) => self.jump(dest),
+            Action::Halt(code) => {
+                self.done = true;
+                self.exit_code = Some(code);
+                Ok(self.cur)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fast::FastMachine;
+    use super::super::{Error, Status};
+    use super::*;
+    use std::iter;
+    use std::sync::Mutex;
+
+    fn registry() -> Registry {
+        let mut registry = Registry::new();
+        // dbg a: prints a value to the host log
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&log);
+        registry
+            .register(20, "dbg", &[Param::Val], move |call| {
+                sink.lock().unwrap().push(call.arg(0));
+                Ok(Action::Continue)
+            })
+            .unwrap();
+        // exit a: halts with an exit code
+        registry
+            .register(21, "exit", &[Param::Val], |call| {
+                Ok(Action::Halt(call.arg(0)))
+            })
+            .unwrap();
+        // swap [a], [b]
+        registry
+            .register(22, "swap", &[Param::Addr, Param::Addr], |call| {
+                let (a, b) = (call.arg(0), call.arg(1));
+                let (x, y) = (call.read(a)?, call.read(b)?);
+                call.write(a, y)?;
+                call.write(b, x).map(|_| Action::Continue)
+            })
+            .unwrap();
+        // readadd a, [dest]: reads a value, adds a to it and stores and echoes the sum
+        registry
+            .register(23, "readadd", &[Param::Val, Param::Addr], |call| {
+                let sum = call.input()? + call.arg(0);
+                call.output(sum);
+                call.write(call.arg(1), sum)?;
+                Ok(Action::Continue)
+            })
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn extension_opcodes_run() {
+        let mut machine = Machine::new(vec![
+            22, 13, 14, 2123, 3, 5, 4, 13, 4, 14, 121, 3, 99, 5, 6, 0,
+        ]);
+        machine.set_extensions(registry());
+        machine.relative_base = 10;
+        assert_eq!(machine.run_to_end(vec![4]).unwrap(), &[7, 6, 5]);
+        assert_eq!(machine.memory()[15], 7);
+        assert_eq!(machine.exit_code(), Some(3));
+        assert!(machine.done());
+    }
+
+    #[test]
+    fn extension_fast_machine_sees_writes() {
+        // Loops once; the second time round swap has redirected `out [20]` to
+        // `out [19]` with a write that is not the last one it makes.
+        let mut machine = Machine::new(vec![
+            4, 20, 22, 1, 21, 1005, 22, 15, 1101, 1, 0, 22, 1105, 1, 0, 99, 0, 0, 0, 42, 7, 19, 0,
+        ]);
+        machine.set_extensions(registry());
+        let mut fast = FastMachine::from(machine.clone());
+        assert_eq!(fast.run_to_end(iter::empty()).unwrap(), &[7, 42]);
+        assert_eq!(machine.run_to_end(iter::empty()).unwrap(), &[7, 42]);
+    }
+
+    #[test]
+    fn extension_eof_retries() {
+        let mut machine = Machine::new(vec![123, 10, 7, 4, 7, 99, 0, 0]);
+        machine.set_extensions(registry());
+        assert_eq!(machine.resume(), Ok(Status::NeedsInput));
+        assert_eq!(machine.resume_with(5), Ok(Status::Output(15)));
+        assert_eq!(machine.resume(), Ok(Status::Output(15)));
+        assert_eq!(machine.resume(), Ok(Status::Halted));
+    }
+
+    #[test]
+    fn extension_unknown_without_registry() {
+        let err = Machine::new(vec![20, 1, 99])
+            .run_to_end(iter::empty())
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error {
+                kind: ErrorKind::UnknownOpcode { opcode: 20 },
+                ..
+            }
+        ));
+        let mut machine = Machine::new(vec![24, 1, 99]);
+        machine.set_extensions(registry());
+        assert_eq!(
+            machine.run_to_end(iter::empty()).unwrap_err().kind,
+            ErrorKind::UnknownOpcode { opcode: 24 }
+        );
+    }
+
+    #[test]
+    fn extension_register_errors() {
+        let mut registry = registry();
+        let noop = |_: &mut Call| Ok(Action::Continue);
+        assert_eq!(
+            registry.register(1, "add2", &[], noop),
+            Err(RegisterError::Builtin(1))
+        );
+        assert_eq!(
+            registry.register(20, "dbg2", &[], noop),
+            Err(RegisterError::Duplicate(20))
+        );
+        assert_eq!(
+            registry.register(100, "big", &[], noop),
+            Err(RegisterError::InvalidOpcode(100))
+        );
+        assert_eq!(
+            registry.register(30, "wide", &[Param::Val; 9], noop),
+            Err(RegisterError::TooManyParams(9))
+        );
+        let names: Vec<_> = registry.iter().map(|ext| ext.name.as_str()).collect();
+        assert_eq!(names, &["dbg", "exit", "swap", "readadd"]);
+    }
+}
@@ -0,0 +1,266 @@
+use super::disasm::{Mode, Op, Operand, Param, MAX_INSTRUCTION_LEN};
+use super::io::{IntcodeInput, IntcodeOutput};
+use super::{arg_mode, Error, ErrorKind, Machine, Status};
+use std::collections::HashMap;
+
+const PAGE_BITS: usize = 6;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+
+type Page = Box<[Option<Decoded>]>;
+
+// An instruction whose opcode and parameter modes have already been checked,
+// so executing it never needs to look at the instruction word again.
+#[derive(Debug, Copy, Clone)]
+struct Decoded {
+    op: Op,
+    args: [Operand; 3],
+}
+
+// Runs the same machine as `Machine::run_io`, but caches decoded instructions
+// per address. A write invalidates every cached instruction that covers the
+// written cell; anything that does not decode cleanly, including extension
+// opcodes, is handed to the regular interpreter so errors are reported
+// identically. The cache only holds pages that have decoded instructions, so
+// code far out in memory costs no more than code at the start; pages are
+// smaller than those of `Memory` because short runs only ever decode a handful
+// of instructions.
+#[derive(Debug, Clone)]
+pub struct FastMachine {
+    machine: Machine,
+    cache: HashMap<usize, Page>,
+}
+
+impl From<Machine> for FastMachine {
+    fn from(machine: Machine) -> Self {
+        FastMachine {
+            machine,
+            cache: HashMap::new(),
+        }
+    }
+}
+
+impl FastMachine {
+    pub fn new(code: Vec<i64>) -> Self {
+        Machine::new(code).into()
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn into_machine(self) -> Machine {
+        self.machine
+    }
+
+    pub fn run_to_end<I>(&mut self, input: I) -> Result<Vec<i64>, Error>
+    where
+        I: IntoIterator<Item = i64>,
+    {
+        let mut output = Vec::new();
+        match self.run_io(&mut input.into_iter(), &mut output)? {
+            Status::NeedsInput => Err(self.machine.locate(ErrorKind::Eof)),
+            _ => Ok(output),
+        }
+    }
+
+    pub fn run_io<I, O>(&mut self, input: &mut I, output: &mut O) -> Result<Status, Error>
+    where
+        I: IntcodeInput,
+        O: IntcodeOutput,
+    {
+        while !self.machine.done {
+            match self.step(input, output) {
+                Ok(()) => {}
+                Err(ErrorKind::Eof) => return Ok(Status::NeedsInput),
+                Err(kind) => return Err(self.machine.locate(kind)),
+            }
+        }
+        Ok(Status::Halted)
+    }
+
+    fn step(
+        &mut self,
+        input: &mut impl IntcodeInput,
+        output: &mut impl IntcodeOutput,
+    ) -> Result<(), ErrorKind> {
+        let ret = match self.decoded(self.machine.cur) {
+            // Access checks and execution tracking live in the interpreter.
+            Some(_) if self.machine.protection.is_some() => self.machine.step_inner(input, output),
+            Some(inst) => self.execute(inst, input, output),
+            // Extension opcodes may write more than the one cell recorded in
+            // `last_write`, so drop everything after running them.
+            None => {
+                let ret = self.machine.step_inner(input, output);
+                self.cache.clear();
+                return ret;
+            }
+        };
+        if let Some((addr, _)) = self.machine.last_write {
+            self.invalidate(addr);
+        }
+        ret
+    }
+
+    fn decoded(&mut self, addr: usize) -> Option<Decoded> {
+        let (idx, offset) = (addr >> PAGE_BITS, addr % PAGE_SIZE);
+        if let Some(inst) = self.cache.get(&idx).and_then(|page| page[offset]) {
+            return Some(inst);
+        }
+        let inst = decode(&self.machine, addr)?;
+        self.cache
+            .entry(idx)
+            .or_insert_with(|| vec![None; PAGE_SIZE].into_boxed_slice())[offset] = Some(inst);
+        Some(inst)
+    }
+
+    fn invalidate(&mut self, addr: usize) {
+        let start = (addr + 1).saturating_sub(MAX_INSTRUCTION_LEN);
+        for addr in start..=addr {
+            if let Some(page) = self.cache.get_mut(&(addr >> PAGE_BITS)) {
+                page[addr % PAGE_SIZE] = None;
+            }
+        }
+    }
+
+    fn execute(
+        &mut self,
+        inst: Decoded,
+        input: &mut impl IntcodeInput,
+        output: &mut impl IntcodeOutput,
+    ) -> Result<(), ErrorKind> {
+        let m = &mut self.machine;
+        m.check_budget()?;
+        m.last_write = None;
+        m.journal_begin();
+        let ip = m.cur;
+        match m.run_decoded(inst, input, output) {
+            Ok(next) => m.cur = next,
+            Err(kind) => {
+                m.journal_abort();
+                return Err(kind);
+            }
+        }
+        m.trail.push(ip);
+        m.executed += 1;
+        Ok(())
+    }
+}
+
+fn decode(machine: &Machine, addr: usize) -> Option<Decoded> {
+    let word = machine.code.get(addr)?;
+    let op = Op::from_opcode(word % 100)?;
+    let mut args = [Operand {
+        mode: Mode::Immediate,
+        value: 0,
+    }; 3];
+    for (idx, param) in op.params().iter().enumerate() {
+        let mode = Mode::from_digit(arg_mode(word, idx))?;
+        if *param == Param::Addr && mode == Mode::Immediate {
+            return None;
+        }
+        args[idx] = Operand {
+            mode,
+            value: machine.code.get(addr + idx + 1)?,
+        };
+    }
+    Some(Decoded { op, args })
+}
+
+impl Machine {
+    fn run_decoded(
+        &mut self,
+        inst: Decoded,
+        input: &mut impl IntcodeInput,
+        output: &mut impl IntcodeOutput,
+    ) -> Result<usize, ErrorKind> {
+        let m = self;
+        let [a, b, c] = inst.args;
+        match inst.op {
+            Op::Add => {
+                let val = m.add(&m.read(a)?, &m.read(b)?)?;
+                m.write(c, val)?;
+                m.inc(4)
+            }
+            Op::Mul => {
+                let val = m.mul(&m.read(a)?, &m.read(b)?)?;
+                m.write(c, val)?;
+                m.inc(4)
+            }
+            Op::Lt => {
+                let val = (m.read(a)? < m.read(b)?) as i64;
+                m.write(c, val)?;
+                m.inc(4)
+            }
+            Op::Eq => {
+                let val = (m.read(a)? == m.read(b)?) as i64;
+                m.write(c, val)?;
+                m.inc(4)
+            }
+            Op::In => {
+                let val = input.next_input().ok_or(ErrorKind::Eof)?;
+                if let Some(journal) = &mut m.journal {
+                    journal.record_input(&val);
+                }
+                m.write(a, val)?;
+                m.inc(2)
+            }
+            Op::Out => {
+                let val = m.read(a)?;
+                let next = m.inc(2)?;
+                if let Some(journal) = &mut m.journal {
+                    journal.record_output();
+                }
+                output.push_output(val);
+                Ok(next)
+            }
+            Op::Jt | Op::Jf => {
+                let val = m.read(a)?;
+                let dest = m.read(b)?;
+                if (val != 0) == (inst.op == Op::Jt) {
+                    m.jump(dest)
+                } else {
+                    m.inc(3)
+                }
+            }
+            Op::Mrb => {
+                m.relative_base = m.offset(m.relative_base, m.read(a)?)?;
+                m.inc(2)
+            }
+            Op::End => {
+                m.done = true;
+                Ok(m.cur)
+            }
+        }
+    }
+
+    fn read(&self, arg: Operand) -> Result<i64, ErrorKind> {
+        match arg.mode {
+            Mode::Position => self.get(arg.value),
+            Mode::Immediate => Ok(arg.value),
+            Mode::Relative => self.get(self.offset(arg.value, self.relative_base)?),
+        }
+    }
+
+    fn write(&mut self, arg: Operand, val: i64) -> Result<i64, ErrorKind> {
+        match arg.mode {
+            Mode::Relative => self.set(self.offset(arg.value, self.relative_base)?, val),
+            _ => self.set(arg.value, val),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::iter;
+
+    #[test]
+    fn fast_cache_is_sparse() {
+        let far = 60_000_000;
+        let mut fast = FastMachine::new(vec![1101, 99, 0, far, 1105, 1, far]);
+        assert_eq!(fast.run_to_end(iter::empty()), Ok(vec![]));
+        let mut pages = fast.cache.keys().copied().collect::<Vec<_>>();
+        pages.sort_unstable();
+        assert_eq!(pages, &[0, far as usize / PAGE_SIZE]);
+    }
+}
@@ -0,0 +1,18 @@
+#![cfg(test)]
+
+use super::asm::assemble;
+
+// Outputs 3, 2 and 1 from a counter in its last cell.
+pub const COUNTDOWN: &str = "
+    loop: out [n]
+          add [n], #-1, [n]
+          jt [n], #loop
+          end
+    n:    .data 3
+";
+
+pub fn countdown(n: i64) -> Vec<i64> {
+    let mut code = assemble(COUNTDOWN).unwrap();
+    *code.last_mut().unwrap() = n;
+    code
+}
@@ -0,0 +1,342 @@
+use super::cell::Arithmetic;
+use super::fast::FastMachine;
+use super::{Budget, Error, ErrorKind, Machine, Status};
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+const ENGINES: [Engine; 3] = [Engine::Interpreter, Engine::Resume, Engine::Fast];
+const ARITHMETIC: [Arithmetic; 2] = [Arithmetic::Wrapping, Arithmetic::Checked];
+
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // An all-zero state would only ever produce zeroes.
+        XorShift(seed.max(1))
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: u64) -> i64 {
+        (self.next() % n) as i64
+    }
+}
+
+// Mostly well-formed instructions with operands pointing back into the program,
+// sprinkled with garbage words so that error paths get exercised too.
+pub fn random_program(rng: &mut XorShift, len: usize) -> Vec<i64> {
+    // `end` is left out, or most programs would halt long before the one
+    // appended at the end.
+    const OPCODES: [i64; 9] = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+    let mut code = Vec::with_capacity(len + 4);
+    let mut starts = vec![0];
+    while code.len() < len {
+        if rng.below(16) == 0 {
+            code.push(rng.below(2000) - 1000);
+            continue;
+        }
+        let opcode = OPCODES[rng.below(OPCODES.len() as u64) as usize];
+        let params = match opcode {
+            1 | 2 | 7 | 8 => 3,
+            5 | 6 => 2,
+            _ => 1,
+        };
+        starts.push(code.len() as i64);
+        let mut word = opcode;
+        let mut operands = Vec::new();
+        for idx in 0..params {
+            let is_dest = idx + 1 == params && ![4, 5, 6, 9].contains(&opcode);
+            let is_target = idx == 1 && (opcode == 5 || opcode == 6);
+            let mode = match rng.below(3) {
+                1 if is_dest && rng.below(8) != 0 => 0,
+                _ if is_target && rng.below(4) != 0 => 1,
+                mode => mode,
+            };
+            word += mode * 10_i64.pow(2 + idx);
+            operands.push(match mode {
+                1 if is_target => starts[rng.below(starts.len() as u64) as usize],
+                1 => rng.below(len as u64) - 2,
+                2 => rng.below(len as u64 + 4) - 2,
+                _ => rng.below(len as u64 + 4),
+            });
+        }
+        code.push(word);
+        code.extend(operands);
+    }
+    code.push(99);
+    code
+}
+
+// Unstructured words: opcodes with arbitrary (often invalid) mode digits,
+// small and negative numbers and values at the edges of the `i64` range.
+pub fn random_words(rng: &mut XorShift, len: usize) -> Vec<i64> {
+    const EDGES: [i64; 6] = [i64::MIN, i64::MIN + 1, -1, 0, i64::MAX - 1, i64::MAX];
+    (0..len)
+        .map(|_| match rng.below(8) {
+            0..=2 => {
+                rng.below(100_000) * 100
+                    + match rng.below(11) {
+                        10 => 99,
+                        op => op,
+                    }
+            }
+            3..=5 => rng.below(len as u64 + 8) - 4,
+            6 => EDGES[rng.below(EDGES.len() as u64) as usize],
+            _ => rng.next() as i64,
+        })
+        .collect()
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Engine {
+    Interpreter,
+    Resume,
+    Fast,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Config {
+    pub iterations: usize,
+    pub seed: u64,
+    pub max_len: usize,
+    pub memory_limit: usize,
+    pub max_instructions: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            iterations: 1000,
+            seed: 0x2545_f491_4f6c_dd1d,
+            max_len: 48,
+            memory_limit: 128,
+            max_instructions: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Failure {
+    pub program: Vec<i64>,
+    pub input: Vec<i64>,
+    pub arithmetic: Arithmetic,
+    pub engine: Engine,
+    pub message: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let join = |vals: &[i64]| {
+            vals.iter()
+                .map(i64::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        writeln!(
+            f,
+            "{:?} engine with {:?} arithmetic: {}",
+            self.engine, self.arithmetic, self.message
+        )?;
+        writeln!(f, "  program: {}", join(&self.program))?;
+        write!(f, "  input: {}", join(&self.input))
+    }
+}
+
+// Everything observable about a finished run, compared across engines.
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    ret: Result<Status, Error>,
+    output: Vec<i64>,
+    cur: usize,
+    relative_base: i64,
+    done: bool,
+    executed: u64,
+}
+
+pub fn run(config: &Config) -> Result<usize, Failure> {
+    let mut rng = XorShift::new(config.seed);
+    for n in 0..config.iterations {
+        let len = rng.below(config.max_len as u64 + 1) as usize;
+        let program = if n % 2 == 0 {
+            random_program(&mut rng, len)
+        } else {
+            random_words(&mut rng, len)
+        };
+        let input: Vec<_> = (0..rng.below(5)).map(|_| rng.below(40) - 10).collect();
+        check(&program, &input, config)?;
+    }
+    Ok(config.iterations)
+}
+
+// Runs a program on every engine under both arithmetic policies and checks that
+// none of them panics, that they stay within the instruction limit and that
+// they all agree on the outcome.
+pub fn check(program: &[i64], input: &[i64], config: &Config) -> Result<(), Failure> {
+    for &arithmetic in &ARITHMETIC {
+        let mut expected: Option<Outcome> = None;
+        for &engine in &ENGINES {
+            let fail = |message| Failure {
+                program: program.to_vec(),
+                input: input.to_vec(),
+                arithmetic,
+                engine,
+                message,
+            };
+            let mut machine = Machine::with_memory_limit(program.to_vec(), config.memory_limit);
+            machine.set_arithmetic(arithmetic);
+            machine.set_budget(Budget::instructions(config.max_instructions));
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| execute(engine, machine, input)))
+                .map_err(|payload| fail(format!("panicked: {}", panic_message(&*payload))))?;
+            verify(&outcome, arithmetic, config).map_err(fail)?;
+            match &expected {
+                None => expected = Some(outcome),
+                Some(expected) if *expected != outcome => {
+                    return Err(fail(format!(
+                        "disagrees with the interpreter: {:?} vs {:?}",
+                        outcome, expected
+                    )))
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn execute(engine: Engine, mut machine: Machine, input: &[i64]) -> Outcome {
+    let mut output = Vec::new();
+    let ret = match engine {
+        Engine::Interpreter => machine.run_io(&mut input.iter().copied(), &mut output),
+        Engine::Fast => {
+            let mut fast = FastMachine::from(machine);
+            let ret = fast.run_io(&mut input.iter().copied(), &mut output);
+            machine = fast.into_machine();
+            ret
+        }
+        Engine::Resume => {
+            input.iter().for_each(|&val| machine.push_input(val));
+            loop {
+                match machine.resume() {
+                    Ok(Status::Output(val)) => output.push(val),
+                    ret => break ret,
+                }
+            }
+        }
+    };
+    Outcome {
+        ret,
+        output,
+        cur: machine.cur(),
+        relative_base: machine.relative_base(),
+        done: machine.done(),
+        executed: machine.executed(),
+    }
+}
+
+fn verify(outcome: &Outcome, arithmetic: Arithmetic, config: &Config) -> Result<(), String> {
+    if outcome.executed > config.max_instructions {
+        return Err(format!(
+            "executed {} instructions with a limit of {}",
+            outcome.executed, config.max_instructions
+        ));
+    }
+    match &outcome.ret {
+        Ok(Status::Halted) if !outcome.done => Err("halted without finishing".to_string()),
+        Ok(Status::NeedsInput) if outcome.done => Err("finished but wants input".to_string()),
+        Ok(Status::Output(_)) => Err("returned an output status".to_string()),
+        Ok(_) => Ok(()),
+        Err(err) if err.ip != outcome.cur => Err(format!(
+            "error located at {} but stopped at {}",
+            err.ip, outcome.cur
+        )),
+        Err(Error {
+            kind: ErrorKind::InstructionLimit { executed },
+            ..
+        }) if *executed != config.max_instructions => Err(format!(
+            "stopped by the instruction limit after {} instructions",
+            executed
+        )),
+        Err(Error {
+            kind: ErrorKind::Overflow,
+            ..
+        }) if arithmetic == Arithmetic::Wrapping => {
+            Err("overflow reported with wrapping arithmetic".to_string())
+        }
+        Err(_) => Ok(()),
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzz_random_programs() {
+        let config = Config {
+            iterations: 3000,
+            ..Config::default()
+        };
+        if let Err(failure) = run(&config) {
+            panic!("{}", failure);
+        }
+    }
+
+    #[test]
+    fn fuzz_edge_cases() {
+        let config = Config {
+            memory_limit: 0,
+            ..Config::default()
+        };
+        let programs: [&[i64]; 8] = [
+            &[],
+            &[4],
+            &[1105, 1, 0],
+            &[i64::MIN],
+            &[109, i64::MAX, 109, 1, 99],
+            &[1101, i64::MAX, i64::MAX, 0, 99],
+            &[204, i64::MIN, 99],
+            &[99_999_999_999, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+        ];
+        for program in &programs {
+            if let Err(failure) = check(program, &[1, 2], &config) {
+                panic!("{}", failure);
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_verify_instruction_limit() {
+        let failure = verify(
+            &Outcome {
+                ret: Ok(Status::Halted),
+                output: Vec::new(),
+                cur: 0,
+                relative_base: 0,
+                done: false,
+                executed: 501,
+            },
+            Arithmetic::Wrapping,
+            &Config::default(),
+        );
+        assert_eq!(
+            failure,
+            Err("executed 501 instructions with a limit of 500".to_string())
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use std::io::{self as stdio, BufRead, Write};
+use std::sync::mpsc::Sender;
+
+pub trait IntcodeInput<C = i64> {
+    fn next_input(&mut self) -> Option<C>;
+}
+
+pub trait IntcodeOutput<C = i64> {
+    fn push_output(&mut self, value: C);
+}
+
+impl<C, I: Iterator<Item = C>> IntcodeInput<C> for I {
+    fn next_input(&mut self) -> Option<C> {
+        self.next()
+    }
+}
+
+impl<C> IntcodeOutput<C> for Vec<C> {
+    fn push_output(&mut self, value: C) {
+        self.push(value);
+    }
+}
+
+impl<C, F: FnMut(C)> IntcodeOutput<C> for F {
+    fn push_output(&mut self, value: C) {
+        self(value)
+    }
+}
+
+impl<C> IntcodeOutput<C> for Sender<C> {
+    fn push_output(&mut self, value: C) {
+        let _ = self.send(value);
+    }
+}
+
+pub fn ascii(text: &str) -> impl Iterator<Item = i64> + '_ {
+    text.bytes().map(i64::from)
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct AsciiOutput {
+    pub text: String,
+    pub values: Vec<i64>,
+}
+
+impl IntcodeOutput for AsciiOutput {
+    fn push_output(&mut self, value: i64) {
+        match value {
+            0..=127 => self.text.push(value as u8 as char),
+            _ => self.values.push(value),
+        }
+    }
+}
+
+// Reads whitespace-separated integers from a reader a line at a time, so that a
+// program can be driven interactively from a terminal. A read error or a word
+// that isn't an integer ends the input and is kept for `finish`.
+pub struct ReaderInput<R> {
+    reader: R,
+    pending: Vec<i64>,
+    error: Option<stdio::Error>,
+}
+
+impl<R: BufRead> ReaderInput<R> {
+    pub fn new(reader: R) -> Self {
+        ReaderInput {
+            reader,
+            pending: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn finish(self) -> stdio::Result<R> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.reader),
+        }
+    }
+}
+
+impl<R: BufRead> IntcodeInput for ReaderInput<R> {
+    fn next_input(&mut self) -> Option<i64> {
+        while self.pending.is_empty() {
+            if self.error.is_some() {
+                return None;
+            }
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => {
+                    self.error = Some(e);
+                    return None;
+                }
+            }
+            let words = line
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|w| !w.is_empty());
+            for word in words {
+                match word.parse() {
+                    Ok(val) => self.pending.push(val),
+                    Err(_) => {
+                        self.error = Some(stdio::Error::new(
+                            stdio::ErrorKind::InvalidData,
+                            format!("invalid input value `{}`", word),
+                        ));
+                        break;
+                    }
+                }
+            }
+            self.pending.reverse();
+        }
+        self.pending.pop()
+    }
+}
+
+pub struct WriterOutput<W> {
+    writer: W,
+    error: Option<stdio::Error>,
+}
+
+impl<W: Write> WriterOutput<W> {
+    pub fn new(writer: W) -> Self {
+        WriterOutput {
+            writer,
+            error: None,
+        }
+    }
+
+    pub fn finish(self) -> stdio::Result<W> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.writer),
+        }
+    }
+}
+
+impl<W: Write> IntcodeOutput for WriterOutput<W> {
+    fn push_output(&mut self, value: i64) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.writer, "{}", value).and_then(|_| self.writer.flush()) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Machine, Status};
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::iter;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+
+    const DOUBLER: [i64; 12] = [3, 11, 1002, 11, 2, 11, 4, 11, 1105, 1, 0, 0];
+
+    #[test]
+    fn io_callback_feeds_back_input() {
+        let queue = Rc::new(RefCell::new(VecDeque::from(vec![1])));
+        let mut seen = Vec::new();
+        let mut input = iter::from_fn(|| queue.borrow_mut().pop_front());
+        let mut output = |v| {
+            seen.push(v);
+            if v < 100 {
+                queue.borrow_mut().push_back(v);
+            }
+        };
+        let mut machine = Machine::new(DOUBLER.to_vec());
+        assert!(machine.run_io(&mut input, &mut output).is_ok());
+        assert_eq!(seen, &[2, 4, 8, 16, 32, 64, 128]);
+    }
+
+    #[test]
+    fn io_channels() {
+        let (in_tx, in_rx) = mpsc::channel();
+        let (mut out_tx, out_rx) = mpsc::channel();
+        in_tx.send(5).unwrap();
+        in_tx.send(6).unwrap();
+        drop(in_tx);
+        let mut machine = Machine::new(DOUBLER.to_vec());
+        machine.run_io(&mut in_rx.iter(), &mut out_tx).unwrap();
+        drop(out_tx);
+        assert_eq!(out_rx.iter().collect::<Vec<_>>(), &[10, 12]);
+    }
+
+    #[test]
+    fn io_ascii_output_splits_values() {
+        let mut machine = Machine::new(vec![
+            3, 13, 4, 13, 1005, 13, 0, 104, 1000, 104, 10, 99, 0, 0,
+        ]);
+        let mut output = AsciiOutput::default();
+        machine
+            .run_io(&mut ascii("hi").chain(iter::once(0)), &mut output)
+            .unwrap();
+        assert_eq!(output.text, "hi\0\n");
+        assert_eq!(output.values, &[1000]);
+    }
+
+    #[test]
+    fn io_reader_and_writer() {
+        let mut input = ReaderInput::new("1 2\n\n3,4\n".as_bytes());
+        let mut output = WriterOutput::new(Vec::new());
+        let mut machine = Machine::new(DOUBLER.to_vec());
+        machine.run_io(&mut input, &mut output).unwrap();
+        assert_eq!(output.finish().unwrap(), b"2\n4\n6\n8\n");
+        assert!(input.finish().is_ok());
+    }
+
+    #[derive(Debug)]
+    struct Failing;
+
+    impl stdio::Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> stdio::Result<usize> {
+            Err(stdio::Error::other("disconnected"))
+        }
+    }
+
+    #[test]
+    fn io_reader_reports_errors() {
+        let mut input = ReaderInput::new("1 2x 3\n4\n".as_bytes());
+        let mut output = Vec::new();
+        let mut machine = Machine::new(DOUBLER.to_vec());
+        assert_eq!(
+            machine.run_io(&mut input, &mut output),
+            Ok(Status::NeedsInput)
+        );
+        assert_eq!(output, &[2]);
+        let err = input.finish().unwrap_err();
+        assert_eq!(err.kind(), stdio::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "invalid input value `2x`");
+
+        let mut input = ReaderInput::new(stdio::BufReader::new(Failing));
+        assert_eq!(input.next_input(), None);
+        assert_eq!(input.finish().unwrap_err().to_string(), "disconnected");
+    }
+}
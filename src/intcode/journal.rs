@@ -0,0 +1,444 @@
+use super::cell::Cell;
+use super::error::Trail;
+use super::memory::Memory;
+use super::protect::Protection;
+use super::Machine;
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+
+pub const DEFAULT_INTERVAL: usize = 1024;
+pub const DEFAULT_CHECKPOINTS: usize = 64;
+
+#[derive(Debug, Clone)]
+struct Registers {
+    cur: usize,
+    relative_base: i64,
+    done: bool,
+    executed: u64,
+    exit_code: Option<i64>,
+    trail: Trail,
+}
+
+// How to undo one step: the registers before it, the old value of every cell
+// it wrote (in order), the input it consumed and the cells it was the first to
+// execute.
+#[derive(Debug, Clone)]
+struct Entry<C> {
+    regs: Registers,
+    len: usize,
+    writes: Vec<(usize, C)>,
+    input: Vec<C>,
+    outputs: usize,
+    executed: Vec<usize>,
+}
+
+// A full copy of the machine state plus the input consumed after it, enough to
+// replay up to the next checkpoint.
+#[derive(Debug, Clone)]
+struct Checkpoint<C> {
+    regs: Registers,
+    memory: Memory<C>,
+    input: Vec<C>,
+    executed: Vec<u64>,
+}
+
+// Undo entries are only kept for the steps since the newest checkpoint.
+// Stepping back past it replays the previous segment from its checkpoint, so
+// memory use is bounded by `interval` entries and `max_checkpoints` copies of
+// memory, and history reaches back roughly `interval * max_checkpoints` steps.
+#[derive(Clone)]
+pub struct Journal<C = i64> {
+    interval: usize,
+    max_checkpoints: usize,
+    checkpoints: VecDeque<Checkpoint<C>>,
+    entries: Vec<Entry<C>>,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Undone {
+    pub steps: u64,
+    pub outputs: usize,
+}
+
+impl<C> Journal<C> {
+    pub fn interval(&self) -> usize {
+        self.interval
+    }
+
+    pub fn max_checkpoints(&self) -> usize {
+        self.max_checkpoints
+    }
+
+    pub fn checkpoints(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    // Number of steps that can currently be undone.
+    pub fn len(&self) -> usize {
+        self.entries.len() + (self.checkpoints.len() - 1) * self.interval
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(super) fn record_write(&mut self, addr: usize, old: C) {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.writes.push((addr, old));
+        }
+    }
+
+    pub(super) fn record_input(&mut self, val: &C)
+    where
+        C: Clone,
+    {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.input.push(val.clone());
+            if let Some(checkpoint) = self.checkpoints.back_mut() {
+                checkpoint.input.push(val.clone());
+            }
+        }
+    }
+
+    pub(super) fn record_output(&mut self) {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.outputs += 1;
+        }
+    }
+
+    pub(super) fn record_executed(&mut self, addr: usize) {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.executed.push(addr);
+        }
+    }
+}
+
+impl<C> fmt::Debug for Journal<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Journal")
+            .field("interval", &self.interval)
+            .field("checkpoints", &self.checkpoints.len())
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+impl<C: Cell> Machine<C> {
+    pub fn enable_journal(&mut self, interval: usize, max_checkpoints: usize) {
+        let mut journal = Journal {
+            interval: interval.max(1),
+            max_checkpoints: max_checkpoints.max(1),
+            checkpoints: VecDeque::new(),
+            entries: Vec::new(),
+        };
+        journal.checkpoints.push_back(self.checkpoint());
+        self.journal = Some(Box::new(journal));
+    }
+
+    pub fn disable_journal(&mut self) {
+        self.journal = None;
+    }
+
+    pub fn journal(&self) -> Option<&Journal<C>> {
+        self.journal.as_deref()
+    }
+
+    // Undoes the last step. Input it consumed goes back to the front of the
+    // input queue so that `resume` reads it again; outputs cannot be taken
+    // back, so only their number is reported.
+    pub fn step_back(&mut self) -> Option<Undone> {
+        let journal = self.journal.as_mut()?;
+        if journal.entries.is_empty() {
+            if journal.checkpoints.len() < 2 {
+                return None;
+            }
+            journal.checkpoints.pop_back();
+            self.replay();
+        }
+        let journal = self.journal.as_mut()?;
+        let entry = journal.entries.pop()?;
+        if let Some(checkpoint) = journal.checkpoints.back_mut() {
+            let len = checkpoint.input.len() - entry.input.len();
+            checkpoint.input.truncate(len);
+        }
+        for (addr, old) in entry.writes.into_iter().rev() {
+            self.code.set(addr, old);
+        }
+        self.code.truncate(entry.len);
+        if let Some(protection) = &mut self.protection {
+            for addr in entry.executed {
+                protection.unmark_executed(addr);
+            }
+        }
+        self.set_registers(entry.regs);
+        for val in entry.input.into_iter().rev() {
+            self.input_queue.push_front(val);
+        }
+        Some(Undone {
+            steps: 1,
+            outputs: entry.outputs,
+        })
+    }
+
+    // Steps back until the instruction pointer is at `addr` or history runs out.
+    pub fn run_back_to(&mut self, addr: usize) -> Undone {
+        let mut ret = Undone::default();
+        while let Some(undone) = self.step_back() {
+            ret.steps += undone.steps;
+            ret.outputs += undone.outputs;
+            if self.cur == addr {
+                break;
+            }
+        }
+        ret
+    }
+
+    pub(super) fn journal_begin(&mut self) {
+        let checkpoint = match &self.journal {
+            None => return,
+            Some(journal) if journal.entries.len() >= journal.interval => Some(self.checkpoint()),
+            Some(_) => None,
+        };
+        let entry = Entry {
+            regs: self.registers(),
+            len: self.code.len(),
+            writes: Vec::new(),
+            input: Vec::new(),
+            outputs: 0,
+            executed: Vec::new(),
+        };
+        if let Some(journal) = &mut self.journal {
+            if let Some(checkpoint) = checkpoint {
+                journal.entries.clear();
+                journal.checkpoints.push_back(checkpoint);
+                if journal.checkpoints.len() > journal.max_checkpoints {
+                    journal.checkpoints.pop_front();
+                }
+            }
+            journal.entries.push(entry);
+        }
+    }
+
+    // A step that failed without side effects (typically waiting for input)
+    // is retried later, so it must not leave an entry behind.
+    pub(super) fn journal_abort(&mut self) {
+        let journal = match &mut self.journal {
+            Some(journal) => journal,
+            None => return,
+        };
+        if !journal
+            .entries
+            .last()
+            .is_some_and(|entry| entry.writes.is_empty() && entry.input.is_empty())
+        {
+            return;
+        }
+        if let (Some(entry), Some(protection)) = (journal.entries.pop(), &mut self.protection) {
+            for addr in entry.executed {
+                protection.unmark_executed(addr);
+            }
+        }
+    }
+
+    // Re-runs the steps since the newest checkpoint to rebuild their entries.
+    // They already passed protection checks and reported any code writes, so
+    // protection only tracks executed cells meanwhile. Extension handlers do
+    // run again, with their output discarded.
+    fn replay(&mut self) {
+        let journal = match &mut self.journal {
+            Some(journal) => journal,
+            None => return,
+        };
+        let interval = journal.interval;
+        let checkpoint = match journal.checkpoints.back_mut() {
+            Some(checkpoint) => checkpoint,
+            None => return,
+        };
+        let input = mem::take(&mut checkpoint.input);
+        let (regs, memory) = (checkpoint.regs.clone(), checkpoint.memory.clone());
+        let executed = checkpoint.executed.clone();
+        self.code = memory;
+        self.set_registers(regs);
+        let budget = mem::take(&mut self.budget);
+        let deadline = self.deadline.take();
+        let protection = self.protection.take();
+        if protection.is_some() {
+            self.protection = Some(Box::new(Protection::replaying(executed)));
+        }
+        let mut input = input.into_iter();
+        let entries = |m: &Self| m.journal.as_ref().map_or(0, |j| j.entries.len());
+        while entries(self) < interval {
+            let before = entries(self);
+            if self.step_inner(&mut input, &mut |_: C| {}).is_err() && entries(self) == before {
+                break;
+            }
+        }
+        self.budget = budget;
+        self.deadline = deadline;
+        self.protection = protection;
+    }
+
+    fn checkpoint(&self) -> Checkpoint<C> {
+        Checkpoint {
+            regs: self.registers(),
+            memory: self.code.clone(),
+            input: Vec::new(),
+            executed: self
+                .protection
+                .as_ref()
+                .map_or_else(Vec::new, |protection| protection.executed_bits().to_vec()),
+        }
+    }
+
+    fn registers(&self) -> Registers {
+        Registers {
+            cur: self.cur,
+            relative_base: self.relative_base,
+            done: self.done,
+            executed: self.executed,
+            exit_code: self.exit_code,
+            trail: self.trail.clone(),
+        }
+    }
+
+    fn set_registers(&mut self, regs: Registers) {
+        self.cur = regs.cur;
+        self.relative_base = regs.relative_base;
+        self.done = regs.done;
+        self.executed = regs.executed;
+        self.exit_code = regs.exit_code;
+        self.trail = regs.trail;
+        self.last_write = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::super::protect::CodeWritePolicy;
+    use super::super::Status;
+    use super::*;
+
+    // Patches the immediate of its own `add` on every pass, so undoing it has
+    // to restore code as well as data.
+    const SELF_MODIFYING: &str = "
+        loop:   add [x], #1, [x]
+        patch:  add [loop+2], #1, [loop+2]
+                out [x]
+                add [n], #-1, [n]
+                jt [n], #loop
+                end
+        x:      .data 0
+        n:      .data 5
+    ";
+
+    fn state(machine: &Machine) -> (Vec<i64>, usize, i64, bool, u64) {
+        (
+            machine.memory().to_vec(),
+            machine.cur(),
+            machine.relative_base(),
+            machine.done(),
+            machine.executed(),
+        )
+    }
+
+    #[test]
+    fn journal_steps_back_to_start() {
+        let code = assemble(SELF_MODIFYING).unwrap();
+        let mut machine = Machine::new(code.clone());
+        machine.enable_journal(DEFAULT_INTERVAL, DEFAULT_CHECKPOINTS);
+        let start = state(&machine);
+        let output = machine.run_to_end(None).unwrap();
+        assert_eq!(output, &[1, 3, 6, 10, 15]);
+        let executed = machine.executed();
+        assert_eq!(machine.journal().unwrap().len() as u64, executed);
+
+        let undone = machine.run_back_to(usize::MAX);
+        assert_eq!(undone.steps, executed);
+        assert_eq!(undone.outputs, 5);
+        assert_eq!(state(&machine), start);
+        assert!(machine.step_back().is_none());
+        assert_eq!(machine.run_to_end(None).unwrap(), output);
+    }
+
+    #[test]
+    fn journal_replays_across_checkpoints() {
+        let code = assemble(SELF_MODIFYING).unwrap();
+        let mut machine = Machine::new(code.clone());
+        machine.enable_journal(4, 3);
+        machine.run_to_end(None).unwrap();
+        let journal = machine.journal().unwrap();
+        assert_eq!(journal.checkpoints(), 3);
+        assert!(journal.len() <= 12);
+
+        for back in 1..=machine.journal().unwrap().len() {
+            let mut reference = Machine::new(code.clone());
+            reference.set_budget(super::super::Budget::instructions(machine.executed() - 1));
+            let _ = reference.run_to_end(None);
+            assert!(machine.step_back().is_some(), "step {}", back);
+            assert_eq!(state(&machine), state(&reference), "step {}", back);
+        }
+        assert!(machine.step_back().is_none());
+    }
+
+    #[test]
+    fn journal_replay_keeps_protection_state() {
+        let code = assemble(SELF_MODIFYING).unwrap();
+        let protected = || {
+            let mut machine = Machine::new(code.clone());
+            machine.set_code_write_policy(CodeWritePolicy::Warn);
+            machine
+        };
+        let executed = |machine: &Machine| {
+            let protection = machine.protection().unwrap();
+            protection.executed().collect::<Vec<_>>()
+        };
+        let mut machine = protected();
+        machine.enable_journal(4, 16);
+        machine.run_to_end(None).unwrap();
+        let warnings = machine.take_code_write_warnings().len();
+        assert_eq!(warnings, 5);
+
+        while machine.step_back().is_some() {
+            let mut reference = protected();
+            reference.set_budget(super::super::Budget::instructions(machine.executed()));
+            let _ = reference.run_to_end(None);
+            assert_eq!(executed(&machine), executed(&reference));
+            assert!(machine.take_code_write_warnings().is_empty());
+        }
+        assert!(executed(&machine).is_empty());
+        machine.run_to_end(None).unwrap();
+        assert_eq!(machine.take_code_write_warnings().len(), warnings);
+    }
+
+    #[test]
+    fn journal_returns_input() {
+        let code =
+            assemble("in [x]\nin [y]\nadd [x], [y], [x]\nout [x]\nend\nx: .data 0\ny: .data 0")
+                .unwrap();
+        let mut machine = Machine::new(code);
+        machine.enable_journal(2, 8);
+        machine.push_input(3);
+        machine.push_input(4);
+        assert_eq!(machine.resume(), Ok(Status::Output(7)));
+        assert_eq!(machine.run_back_to(2).steps, 3);
+        assert_eq!((machine.memory()[11], machine.memory()[12]), (3, 0));
+        assert_eq!(machine.resume(), Ok(Status::Output(7)));
+        machine.run_back_to(0);
+        machine.push_input(10);
+        assert_eq!(machine.resume(), Ok(Status::Output(7)));
+        assert_eq!(machine.resume(), Ok(Status::Halted));
+    }
+
+    #[test]
+    fn journal_ignores_failed_steps() {
+        let mut machine = Machine::new(vec![3, 5, 4, 5, 99, 0]);
+        machine.enable_journal(DEFAULT_INTERVAL, DEFAULT_CHECKPOINTS);
+        assert_eq!(machine.resume(), Ok(Status::NeedsInput));
+        assert_eq!(machine.resume(), Ok(Status::NeedsInput));
+        assert!(machine.journal().unwrap().is_empty());
+        assert_eq!(machine.resume_with(8), Ok(Status::Output(8)));
+        assert_eq!(machine.journal().unwrap().len(), 2);
+    }
+}
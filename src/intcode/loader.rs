@@ -0,0 +1,200 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+// Binary programs are this magic followed by zigzag LEB128 varints, one per cell.
+pub const BINARY_MAGIC: &[u8; 4] = b"ICB1";
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    InvalidToken { offset: usize, token: String },
+    EmptyValue { offset: usize },
+    Truncated { offset: usize },
+    Overflow { offset: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::InvalidToken { offset, token } => {
+                write!(f, "invalid value '{}' at byte {}", token, offset)
+            }
+            LoadError::EmptyValue { offset } => write!(f, "missing value at byte {}", offset),
+            LoadError::Truncated { offset } => {
+                write!(f, "truncated binary value at byte {}", offset)
+            }
+            LoadError::Overflow { offset } => {
+                write!(f, "binary value at byte {} does not fit in 64 bits", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl From<LoadError> for io::Error {
+    fn from(e: LoadError) -> Self {
+        match e {
+            LoadError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+pub fn load_path<P: AsRef<Path>>(path: P) -> Result<Vec<i64>, LoadError> {
+    load_reader(File::open(path)?)
+}
+
+pub fn load_reader<R: Read>(mut reader: R) -> Result<Vec<i64>, LoadError> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    load_bytes(&bytes)
+}
+
+pub fn load_str(text: &str) -> Result<Vec<i64>, LoadError> {
+    load_bytes(text.as_bytes())
+}
+
+pub fn load_bytes(bytes: &[u8]) -> Result<Vec<i64>, LoadError> {
+    if bytes.starts_with(BINARY_MAGIC) {
+        parse_binary(bytes)
+    } else {
+        parse_text(bytes)
+    }
+}
+
+fn is_separator(b: u8) -> bool {
+    b.is_ascii_whitespace() || b == b',' || b == b'#' || b == b';'
+}
+
+// Values may be separated by commas and/or whitespace; '#' and ';' start a
+// comment running to the end of the line.
+fn parse_text(bytes: &[u8]) -> Result<Vec<i64>, LoadError> {
+    let mut ret = Vec::new();
+    let mut pending_comma = true;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b if b.is_ascii_whitespace() => i += 1,
+            b'#' | b';' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b',' => {
+                if pending_comma {
+                    return Err(LoadError::EmptyValue { offset: i });
+                }
+                pending_comma = true;
+                i += 1;
+            }
+            _ => {
+                let start = i;
+                while i < bytes.len() && !is_separator(bytes[i]) {
+                    i += 1;
+                }
+                let token = String::from_utf8_lossy(&bytes[start..i]);
+                let value = token.parse().map_err(|_| LoadError::InvalidToken {
+                    offset: start,
+                    token: token.into_owned(),
+                })?;
+                ret.push(value);
+                pending_comma = false;
+            }
+        }
+    }
+    Ok(ret)
+}
+
+fn parse_binary(bytes: &[u8]) -> Result<Vec<i64>, LoadError> {
+    let mut ret = Vec::new();
+    let mut i = BINARY_MAGIC.len();
+    while i < bytes.len() {
+        let start = i;
+        let mut raw = 0_u64;
+        let mut shift = 0;
+        loop {
+            let b = *bytes.get(i).ok_or(LoadError::Truncated { offset: start })?;
+            i += 1;
+            if shift >= 64 || (shift == 63 && b & 0x7e != 0) {
+                return Err(LoadError::Overflow { offset: start });
+            }
+            raw |= u64::from(b & 0x7f) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+        ret.push((raw >> 1) as i64 ^ -((raw & 1) as i64));
+    }
+    Ok(ret)
+}
+
+pub fn write_binary<W: Write>(code: &[i64], out: &mut W) -> io::Result<()> {
+    let mut buf = BINARY_MAGIC.to_vec();
+    for &val in code {
+        let mut raw = ((val << 1) ^ (val >> 63)) as u64;
+        while raw >= 0x80 {
+            buf.push(raw as u8 | 0x80);
+            raw >>= 7;
+        }
+        buf.push(raw as u8);
+    }
+    out.write_all(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loader_text_tolerates_whitespace_and_comments() {
+        let text = "# countdown\n1101, 1,2 ,3\n\n  104,-7, ; trailing\n99,\n";
+        assert_eq!(load_str(text).unwrap(), &[1101, 1, 2, 3, 104, -7, 99]);
+        assert_eq!(load_str("").unwrap(), &[]);
+    }
+
+    #[test]
+    fn loader_text_errors() {
+        match load_str("1,2,\n3,x4,5") {
+            Err(LoadError::InvalidToken { offset, token }) => {
+                assert_eq!((offset, token.as_str()), (7, "x4"))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            load_str("1,,2"),
+            Err(LoadError::EmptyValue { offset: 2 })
+        ));
+        assert_eq!(
+            load_str("1,99999999999999999999").unwrap_err().to_string(),
+            "invalid value '99999999999999999999' at byte 2"
+        );
+    }
+
+    #[test]
+    fn loader_binary_roundtrip() {
+        let code = vec![0, 1, -1, 63, -64, 64, 1125899906842624, i64::MAX, i64::MIN];
+        let mut buf = Vec::new();
+        write_binary(&code, &mut buf).unwrap();
+        assert_eq!(&buf[..7], b"ICB1\x00\x02\x01");
+        assert_eq!(load_reader(&buf[..]).unwrap(), code);
+
+        buf.pop();
+        assert!(matches!(load_bytes(&buf), Err(LoadError::Truncated { .. })));
+        let overflow = b"ICB1\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f";
+        assert!(matches!(
+            load_bytes(overflow),
+            Err(LoadError::Overflow { offset: 4 })
+        ));
+    }
+}
@@ -0,0 +1,231 @@
+use super::cell::Cell;
+use std::fmt;
+use std::ops::{Index, Range};
+
+const PAGE_BITS: usize = 10;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+
+pub const DEFAULT_LIMIT: usize = 1 << 26;
+// Larger limits are clamped, so that the page table for the highest address
+// still fits in host memory.
+pub const MAX_LIMIT: usize = 1 << 40;
+
+type Page<C> = Box<[C]>;
+
+fn new_page<C: Cell>() -> Page<C> {
+    vec![C::default(); PAGE_SIZE].into_boxed_slice()
+}
+
+#[derive(Clone)]
+pub struct Memory<C = i64> {
+    pages: Vec<Option<Page<C>>>,
+    len: usize,
+    limit: usize,
+    zero: C,
+}
+
+impl Memory {
+    pub fn new(image: Vec<i64>) -> Self {
+        Self::with_limit(image, DEFAULT_LIMIT)
+    }
+
+    pub fn with_limit(image: Vec<i64>, limit: usize) -> Self {
+        Self::from_cells(image, limit)
+    }
+}
+
+impl<C: Cell> Memory<C> {
+    pub fn from_cells(image: Vec<C>, limit: usize) -> Self {
+        let mut ret = Memory {
+            pages: Vec::new(),
+            len: image.len(),
+            limit: limit.min(MAX_LIMIT).max(image.len()),
+            zero: C::default(),
+        };
+        for chunk in image.chunks(PAGE_SIZE) {
+            let mut page = new_page();
+            page[..chunk.len()].clone_from_slice(chunk);
+            ret.pages.push(Some(page));
+        }
+        ret
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.min(MAX_LIMIT).max(self.len);
+    }
+
+    pub fn get(&self, addr: usize) -> Option<C> {
+        if addr >= self.limit {
+            return None;
+        }
+        Some(self[addr].clone())
+    }
+
+    pub fn set(&mut self, addr: usize, val: C) -> Option<C> {
+        if addr >= self.limit {
+            return None;
+        }
+        self.len = self.len.max(addr + 1);
+        let idx = addr >> PAGE_BITS;
+        if idx >= self.pages.len() {
+            if val.is_zero() {
+                return Some(C::default());
+            }
+            self.pages.resize_with(idx + 1, || None);
+        }
+        let slot = &mut self.pages[idx];
+        if slot.is_none() && val.is_zero() {
+            return Some(C::default());
+        }
+        let page = slot.get_or_insert_with(new_page);
+        Some(std::mem::replace(&mut page[addr % PAGE_SIZE], val))
+    }
+
+    pub fn grow_to(&mut self, len: usize) {
+        self.len = self.len.max(len.min(self.limit));
+    }
+
+    // Only shortens the logical length; cells past it must already be zero.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    pub fn nonzero(&self) -> impl Iterator<Item = (usize, C)> + '_ {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, page)| page.as_ref().map(|page| (idx << PAGE_BITS, page)))
+            .flat_map(|(base, page)| {
+                page.iter()
+                    .enumerate()
+                    .filter(|(_, val)| !val.is_zero())
+                    .map(move |(offset, val)| (base + offset, val.clone()))
+            })
+    }
+
+    // The address ranges of allocated pages, which cover the loaded image and
+    // everything written since, clipped to the logical length.
+    pub fn allocated(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter(|(_, page)| page.is_some())
+            .map(move |(idx, _)| idx << PAGE_BITS..((idx + 1) << PAGE_BITS).min(self.len))
+            .filter(|range| !range.is_empty())
+    }
+
+    pub fn diff(&self, other: &Memory<C>) -> Vec<(usize, C, C)> {
+        let mut ret = Vec::new();
+        for idx in 0..self.pages.len().max(other.pages.len()) {
+            let ours = self.pages.get(idx).and_then(Option::as_ref);
+            let theirs = other.pages.get(idx).and_then(Option::as_ref);
+            if ours.is_none() && theirs.is_none() {
+                continue;
+            }
+            for offset in 0..PAGE_SIZE {
+                let old = ours.map_or(&self.zero, |page| &page[offset]);
+                let new = theirs.map_or(&self.zero, |page| &page[offset]);
+                if old != new {
+                    ret.push(((idx << PAGE_BITS) + offset, old.clone(), new.clone()));
+                }
+            }
+        }
+        ret
+    }
+
+    pub fn to_vec(&self) -> Vec<C> {
+        (0..self.len).map(|addr| self[addr].clone()).collect()
+    }
+
+    fn page(&self, addr: usize) -> Option<&Page<C>> {
+        self.pages.get(addr >> PAGE_BITS).and_then(Option::as_ref)
+    }
+}
+
+impl<C: Cell> Index<usize> for Memory<C> {
+    type Output = C;
+
+    fn index(&self, addr: usize) -> &C {
+        assert!(addr < self.limit, "address {} exceeds memory limit", addr);
+        self.page(addr)
+            .map_or(&self.zero, |page| &page[addr % PAGE_SIZE])
+    }
+}
+
+impl<C> fmt::Debug for Memory<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Memory")
+            .field("len", &self.len)
+            .field("limit", &self.limit)
+            .field("pages", &self.pages.iter().filter(|p| p.is_some()).count())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_unwritten_reads_zero() {
+        let mem = Memory::new(vec![1, 2, 3]);
+        assert_eq!(mem.get(2), Some(3));
+        assert_eq!(mem.get(3), Some(0));
+        assert_eq!(mem.get(1 << 20), Some(0));
+        assert_eq!(mem.len(), 3);
+    }
+
+    #[test]
+    fn memory_sparse_writes() {
+        let mut mem = Memory::new(vec![1]);
+        assert_eq!(mem.set(5_000_000, 7), Some(0));
+        assert_eq!(mem.set(5_000_000, 8), Some(7));
+        assert_eq!(mem.get(5_000_000), Some(8));
+        assert_eq!(mem.len(), 5_000_001);
+        assert_eq!(mem.pages.iter().filter(|p| p.is_some()).count(), 2);
+    }
+
+    #[test]
+    fn memory_zero_write_does_not_allocate() {
+        let mut mem = Memory::new(Vec::new());
+        assert_eq!(mem.set(100_000, 0), Some(0));
+        assert_eq!(mem.len(), 100_001);
+        assert!(mem.pages.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn memory_nonzero_and_diff() {
+        let mut a = Memory::new(vec![0, 3, 0, 4]);
+        let mut b = a.clone();
+        b.set(3, 0);
+        b.set(70_000, 9);
+        a.set(2_000_000, 0);
+        assert_eq!(b.nonzero().collect::<Vec<_>>(), &[(1, 3), (70_000, 9)]);
+        assert_eq!(a.diff(&b), &[(3, 4, 0), (70_000, 0, 9)]);
+    }
+
+    #[test]
+    fn memory_limit() {
+        let mut mem = Memory::with_limit(vec![1, 2], 1);
+        assert_eq!(mem.limit(), 2);
+        assert_eq!(mem.set(2, 1), None);
+        assert_eq!(mem.get(2), None);
+        mem.set_limit(10);
+        assert_eq!(mem.set(9, 1), Some(0));
+        assert_eq!(mem.to_vec(), &[1, 2, 0, 0, 0, 0, 0, 0, 0, 1]);
+        mem.set_limit(usize::MAX);
+        assert_eq!(mem.limit(), MAX_LIMIT);
+    }
+}
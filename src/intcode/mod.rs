@@ -1,23 +1,92 @@
+pub mod asm;
+pub mod bigint;
+pub mod cell;
+pub mod cfg;
+pub mod compile;
+pub mod console;
+pub mod coverage;
+pub mod debugger;
+pub mod disasm;
+mod error;
+pub mod extension;
+pub mod fast;
+mod fixtures;
+pub mod fuzz;
+pub mod io;
+pub mod journal;
+pub mod loader;
+pub mod memory;
+pub mod network;
+pub mod protect;
+pub mod search;
+pub mod signature;
+pub mod snapshot;
 mod tests;
+pub mod trace;
 
+pub use error::{Error, ErrorKind};
+
+use cell::{Arithmetic, Cell};
+use error::Trail;
+use extension::Registry;
+use io::{IntcodeInput, IntcodeOutput};
+use journal::Journal;
+use memory::Memory;
+use protect::{Access, Protection};
+use std::collections::VecDeque;
+use std::iter;
 use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone)]
-pub struct Machine {
-    code: Vec<i64>,
+pub struct Machine<C = i64> {
+    code: Memory<C>,
     cur: usize,
     relative_base: i64,
     done: bool,
+    last_write: Option<(usize, C)>,
+    input_queue: VecDeque<C>,
+    trail: Trail,
+    executed: u64,
+    budget: Budget,
+    budget_start: u64,
+    deadline: Option<Instant>,
+    arithmetic: Arithmetic,
+    extensions: Option<Arc<Registry<C>>>,
+    exit_code: Option<i64>,
+    journal: Option<Box<Journal<C>>>,
+    protection: Option<Box<Protection>>,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Budget {
+    pub max_instructions: Option<u64>,
+    // Measured from the `set_budget` call, not from when the budget was built.
+    pub timeout: Option<Duration>,
+}
+
+impl Budget {
+    pub fn instructions(max: u64) -> Self {
+        Budget {
+            max_instructions: Some(max),
+            timeout: None,
+        }
+    }
+
+    pub fn timeout(duration: Duration) -> Self {
+        Budget {
+            max_instructions: None,
+            timeout: Some(duration),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum Error {
-    UnknownOpcode { opcode: i64 },
-    UnknownOpmode { mode: i64 },
-    InvalidOpmode { mode: i64 },
-    OutOfBounds,
-    Eof,
-    NoTermination,
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Status<C = i64> {
+    NeedsInput,
+    Output(C),
+    Halted,
 }
 
 macro_rules! access_args {
@@ -48,186 +117,409 @@ const OP_EQ: i64 = 8;
 const OP_MRB: i64 = 9;
 const OP_END: i64 = 99;
 
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+fn arg_mode(word: i64, idx: usize) -> i64 {
+    word / 10_i64.pow(2 + idx as u32) % 10
+}
+
+// Cells used as instructions, addresses or relative base offsets must fit in an
+// `i64`.
+fn word<C: Cell>(val: &C) -> Result<i64, ErrorKind> {
+    val.to_i64().ok_or_else(|| ErrorKind::Unrepresentable {
+        value: val.to_string(),
+    })
+}
+
 impl Machine {
     pub fn new(code: Vec<i64>) -> Self {
+        Self::from_cells(code)
+    }
+
+    pub fn with_memory_limit(code: Vec<i64>, limit: usize) -> Self {
+        Self::with_memory(Memory::with_limit(code, limit))
+    }
+
+    pub fn with_initial_size(mut code: Vec<i64>, size: usize) -> Self {
+        code.resize(size, 0);
+        Self::new(code)
+    }
+}
+
+impl<C: Cell> Machine<C> {
+    pub fn from_cells(code: Vec<C>) -> Self {
+        Self::with_memory(Memory::from_cells(code, memory::DEFAULT_LIMIT))
+    }
+
+    fn with_memory(code: Memory<C>) -> Self {
         Machine {
             code,
             cur: 0,
             relative_base: 0,
             done: false,
+            last_write: None,
+            input_queue: VecDeque::new(),
+            trail: Trail::default(),
+            executed: 0,
+            budget: Budget::default(),
+            budget_start: 0,
+            deadline: None,
+            arithmetic: Arithmetic::default(),
+            extensions: None,
+            exit_code: None,
+            journal: None,
+            protection: None,
         }
     }
 
-    pub fn with_initial_size(mut code: Vec<i64>, size: usize) -> Self {
-        code.resize(size, 0);
-        Self::new(code)
-    }
-
-    pub fn run_to_end<I>(&mut self, input: I) -> Result<Vec<i64>, Error>
+    pub fn run_to_end<I>(&mut self, input: I) -> Result<Vec<C>, Error>
     where
-        I: IntoIterator<Item = i64>,
+        I: IntoIterator<Item = C>,
     {
         let mut output = Vec::new();
-        self.cur = 0;
-        self.run_inner_loop(input.into_iter(), &mut output)?;
-        Ok(output)
+        match self.run_io(&mut input.into_iter(), &mut output)? {
+            Status::NeedsInput => Err(self.locate(ErrorKind::Eof)),
+            _ => Ok(output),
+        }
     }
 
-    pub fn run_with<I>(&mut self, input: I, output: &mut Vec<i64>) -> Result<usize, Error>
+    pub fn run_with<I>(&mut self, input: I, output: &mut Vec<C>) -> Result<usize, Error>
     where
-        I: IntoIterator<Item = i64>,
+        I: IntoIterator<Item = C>,
     {
         let output_init_len = output.len();
-        match self.run_inner_loop(input.into_iter(), output) {
-            Ok(_) | Err(Error::Eof) => Ok(output.len() - output_init_len),
-            Err(e) => Err(e),
+        self.run_io(&mut input.into_iter(), output)?;
+        Ok(output.len() - output_init_len)
+    }
+
+    pub fn run_io<I, O>(&mut self, input: &mut I, output: &mut O) -> Result<Status<C>, Error>
+    where
+        I: IntcodeInput<C>,
+        O: IntcodeOutput<C>,
+    {
+        while !self.done {
+            match self.step_inner(input, output) {
+                Ok(()) => {}
+                Err(ErrorKind::Eof) => return Ok(Status::NeedsInput),
+                Err(kind) => return Err(self.locate(kind)),
+            }
         }
+        Ok(Status::Halted)
+    }
+
+    pub fn push_input(&mut self, value: C) {
+        self.input_queue.push_back(value);
+    }
+
+    pub fn resume_with(&mut self, value: C) -> Result<Status<C>, Error> {
+        self.push_input(value);
+        self.resume()
+    }
+
+    pub fn resume(&mut self) -> Result<Status<C>, Error> {
+        let mut queue = mem::take(&mut self.input_queue);
+        let mut input = iter::from_fn(|| queue.pop_front());
+        let mut output = Vec::with_capacity(1);
+        let ret = loop {
+            if self.done {
+                break Ok(Status::Halted);
+            }
+            match self.step_inner(&mut input, &mut output) {
+                Ok(()) => {}
+                Err(ErrorKind::Eof) => break Ok(Status::NeedsInput),
+                Err(kind) => break Err(self.locate(kind)),
+            }
+            if let Some(val) = output.pop() {
+                break Ok(Status::Output(val));
+            }
+        };
+        self.input_queue = queue;
+        ret
+    }
+
+    pub fn memory(&self) -> &Memory<C> {
+        &self.code
+    }
+
+    pub fn set_memory_limit(&mut self, limit: usize) {
+        self.code.set_limit(limit);
+    }
+
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.budget = budget;
+        self.budget_start = self.executed;
+        self.deadline = budget.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    pub fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    pub fn set_arithmetic(&mut self, arithmetic: Arithmetic) {
+        self.arithmetic = arithmetic;
+    }
+
+    pub fn arithmetic(&self) -> Arithmetic {
+        self.arithmetic
+    }
+
+    pub fn executed(&self) -> u64 {
+        self.executed
     }
 
     pub fn done(&self) -> bool {
         self.done
     }
 
-    fn run_inner_loop(
+    pub fn cur(&self) -> usize {
+        self.cur
+    }
+
+    pub fn relative_base(&self) -> i64 {
+        self.relative_base
+    }
+
+    fn locate(&self, kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            ip: self.cur,
+            instruction: self
+                .code
+                .get(self.cur)
+                .and_then(|word| word.to_i64())
+                .unwrap_or_default(),
+            relative_base: self.relative_base,
+            backtrace: self.trail.to_vec(),
+        }
+    }
+
+    fn step_inner(
         &mut self,
-        mut input: impl Iterator<Item = i64>,
-        output: &mut Vec<i64>,
-    ) -> Result<(), Error> {
-        while !self.done {
-            self.cur = match self.code[self.cur] % 100 {
-                OP_ADD => self.bin_op(|x, y| x + y),
-                OP_MUL => self.bin_op(|x, y| x * y),
-                OP_IN => self.store_input(&mut input),
-                OP_OUT => self.output(output),
-                OP_JT => self.jump_if(|x| x != 0),
-                OP_JF => self.jump_if(|x| x == 0),
-                OP_LT => self.compare(|x, y| x < y),
-                OP_EQ => self.compare(|x, y| x == y),
-                OP_MRB => self.modify_relative_base(),
-                OP_END => {
-                    self.done = true;
-                    Ok(self.cur)
-                }
-                n => Err(Error::UnknownOpcode { opcode: n }),
-            }?;
+        input: &mut impl IntcodeInput<C>,
+        output: &mut impl IntcodeOutput<C>,
+    ) -> Result<(), ErrorKind> {
+        self.check_budget()?;
+        self.last_write = None;
+        self.journal_begin();
+        let ip = self.cur;
+        match self.execute(input, output) {
+            Ok(next) => self.cur = next,
+            Err(kind) => {
+                self.journal_abort();
+                return Err(kind);
+            }
         }
+        self.trail.push(ip);
+        self.executed += 1;
         Ok(())
     }
 
-    fn bin_op<F: FnOnce(i64, i64) -> i64>(&mut self, op: F) -> Result<usize, Error> {
+    fn execute(
+        &mut self,
+        input: &mut impl IntcodeInput<C>,
+        output: &mut impl IntcodeOutput<C>,
+    ) -> Result<usize, ErrorKind> {
+        let instruction = self.instruction()?;
+        self.check_execute(instruction)?;
+        match instruction % 100 {
+            OP_ADD => self.bin_op(|m, x, y| m.add(&x, &y)),
+            OP_MUL => self.bin_op(|m, x, y| m.mul(&x, &y)),
+            OP_IN => self.store_input(input),
+            OP_OUT => self.output(output),
+            OP_JT => self.jump_if(|x| !x.is_zero()),
+            OP_JF => self.jump_if(|x| x.is_zero()),
+            OP_LT => self.compare(|x, y| x < y),
+            OP_EQ => self.compare(|x, y| x == y),
+            OP_MRB => self.modify_relative_base(),
+            OP_END => {
+                self.done = true;
+                Ok(self.cur)
+            }
+            n => self.run_extension(n, input, output),
+        }
+    }
+
+    fn check_budget(&self) -> Result<(), ErrorKind> {
+        let executed = self.executed.saturating_sub(self.budget_start);
+        if self
+            .budget
+            .max_instructions
+            .is_some_and(|max| executed >= max)
+        {
+            return Err(ErrorKind::InstructionLimit { executed });
+        }
+        match self.deadline {
+            Some(deadline)
+                if executed.is_multiple_of(DEADLINE_CHECK_INTERVAL)
+                    && Instant::now() >= deadline =>
+            {
+                Err(ErrorKind::DeadlineExceeded { executed })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn bin_op<F>(&mut self, op: F) -> Result<usize, ErrorKind>
+    where
+        F: FnOnce(&Self, C, C) -> Result<C, ErrorKind>,
+    {
         access_args! {self =>
             (let a = arg 0)
             (let b = arg 1)
             (let r_addr = addr_arg 2)
         }
-        self.set(r_addr, op(a, b))?;
+        let val = op(self, a, b)?;
+        self.set(r_addr, val)?;
         self.inc(4)
     }
 
-    fn store_input<I>(&mut self, input: &mut I) -> Result<usize, Error>
-    where
-        I: Iterator<Item = i64>,
-    {
+    fn add(&self, x: &C, y: &C) -> Result<C, ErrorKind> {
+        match self.arithmetic {
+            Arithmetic::Wrapping => Ok(x.wrapping_add(y)),
+            Arithmetic::Checked => x.checked_add(y).ok_or(ErrorKind::Overflow),
+        }
+    }
+
+    fn mul(&self, x: &C, y: &C) -> Result<C, ErrorKind> {
+        match self.arithmetic {
+            Arithmetic::Wrapping => Ok(x.wrapping_mul(y)),
+            Arithmetic::Checked => x.checked_mul(y).ok_or(ErrorKind::Overflow),
+        }
+    }
+
+    // Address arithmetic is always done on `i64` whatever the cell type.
+    fn offset(&self, addr: i64, delta: i64) -> Result<i64, ErrorKind> {
+        match self.arithmetic {
+            Arithmetic::Wrapping => Ok(addr.wrapping_add(delta)),
+            Arithmetic::Checked => addr.checked_add(delta).ok_or(ErrorKind::Overflow),
+        }
+    }
+
+    fn store_input<I: IntcodeInput<C>>(&mut self, input: &mut I) -> Result<usize, ErrorKind> {
         access_args! {self =>
             (let r_addr = addr_arg 0)
         }
-        self.set(r_addr, input.next().ok_or(Error::Eof)?)?;
+        let val = input.next_input().ok_or(ErrorKind::Eof)?;
+        if let Some(journal) = &mut self.journal {
+            journal.record_input(&val);
+        }
+        self.set(r_addr, val)?;
         self.inc(2)
     }
 
-    fn output(&self, out: &mut Vec<i64>) -> Result<usize, Error> {
+    fn output<O: IntcodeOutput<C>>(&mut self, out: &mut O) -> Result<usize, ErrorKind> {
         access_args! {self =>
             (let val = arg 0)
         }
-        out.push(val);
-        self.inc(2)
+        let next = self.inc(2)?;
+        if let Some(journal) = &mut self.journal {
+            journal.record_output();
+        }
+        out.push_output(val);
+        Ok(next)
     }
 
-    fn set(&mut self, idx: i64, val: i64) -> Result<i64, Error> {
+    fn set(&mut self, idx: i64, val: C) -> Result<C, ErrorKind> {
+        let out_of_bounds = ErrorKind::OutOfBounds { addr: idx };
         if idx < 0 {
-            return Err(Error::OutOfBounds);
+            return Err(out_of_bounds);
         }
-        self.code
-            .get_mut(idx as usize)
-            .map(|x| mem::replace(x, val))
-            .ok_or(Error::OutOfBounds)
+        if self.protection.is_some() {
+            self.check_access(idx, Access::Write)?;
+            self.check_code_write(idx as usize)?;
+        }
+        let old = self.code.set(idx as usize, val).ok_or(out_of_bounds)?;
+        self.last_write = Some((idx as usize, old.clone()));
+        if let Some(journal) = &mut self.journal {
+            journal.record_write(idx as usize, old.clone());
+        }
+        Ok(old)
     }
 
-    fn jump_if<F: FnOnce(i64) -> bool>(&self, cond: F) -> Result<usize, Error> {
+    fn jump_if<F: FnOnce(&C) -> bool>(&self, cond: F) -> Result<usize, ErrorKind> {
         access_args! {self =>
             (let val = arg 0)
             (let dest = arg 1)
         }
-        if cond(val) {
-            self.jump(dest)
+        if cond(&val) {
+            self.jump(word(&dest)?)
         } else {
             self.inc(3)
         }
     }
 
-    fn modify_relative_base(&mut self) -> Result<usize, Error> {
+    fn modify_relative_base(&mut self) -> Result<usize, ErrorKind> {
         access_args! {self =>
             (let delta = arg 0)
         }
-        self.relative_base += delta;
+        self.relative_base = self.offset(self.relative_base, word(&delta)?)?;
         self.inc(2)
     }
 
-    fn compare<F: FnOnce(i64, i64) -> bool>(&mut self, comp: F) -> Result<usize, Error> {
-        self.bin_op(|x, y| if comp(x, y) { 1 } else { 0 })
+    fn compare<F: FnOnce(&C, &C) -> bool>(&mut self, comp: F) -> Result<usize, ErrorKind> {
+        self.bin_op(|_, x, y| Ok(C::from(i64::from(comp(&x, &y)))))
     }
 
-    fn jump(&self, loc: i64) -> Result<usize, Error> {
+    fn jump(&self, loc: i64) -> Result<usize, ErrorKind> {
         if loc < 0 || loc as usize >= self.code.len() {
-            Err(Error::OutOfBounds)
+            Err(ErrorKind::OutOfBounds { addr: loc })
         } else {
             Ok(loc as usize)
         }
     }
 
-    fn inc(&self, amount: usize) -> Result<usize, Error> {
+    fn inc(&self, amount: usize) -> Result<usize, ErrorKind> {
         if self.cur + amount >= self.code.len() {
-            Err(Error::NoTermination)
+            Err(ErrorKind::NoTermination)
         } else {
             Ok(self.cur + amount)
         }
     }
 
-    fn get(&self, idx: i64) -> Result<i64, Error> {
+    fn get(&self, idx: i64) -> Result<C, ErrorKind> {
+        self.check_access(idx, Access::Read)?;
+        self.fetch(idx)
+    }
+
+    // Reads a cell of the instruction being executed, which `check_execute`
+    // has already vetted.
+    fn fetch(&self, idx: i64) -> Result<C, ErrorKind> {
+        let out_of_bounds = ErrorKind::OutOfBounds { addr: idx };
         if idx < 0 {
-            return Err(Error::OutOfBounds);
+            return Err(out_of_bounds);
         }
-        self.code
-            .get(idx as usize)
-            .copied()
-            .ok_or(Error::OutOfBounds)
+        self.code.get(idx as usize).ok_or(out_of_bounds)
+    }
+
+    fn get_arg_raw(&self, idx: usize) -> Result<C, ErrorKind> {
+        self.fetch((self.cur + idx + 1) as i64)
     }
 
-    fn get_arg_raw(&self, idx: usize) -> Result<i64, Error> {
-        self.get((self.cur + idx + 1) as i64)
+    fn get_arg_mode(&self, idx: usize) -> Result<i64, ErrorKind> {
+        Ok(arg_mode(self.instruction()?, idx))
     }
 
-    fn get_arg_mode(&self, idx: usize) -> i64 {
-        self.code[self.cur] / 10_i64.pow(2 + idx as u32) % 10
+    fn instruction(&self) -> Result<i64, ErrorKind> {
+        word(&self.fetch(self.cur as i64)?)
     }
 
-    fn get_val_arg(&self, idx: usize) -> Result<i64, Error> {
+    fn get_val_arg(&self, idx: usize) -> Result<C, ErrorKind> {
         let raw = self.get_arg_raw(idx);
-        match self.get_arg_mode(idx) {
-            0 => self.get(raw?),
+        match self.get_arg_mode(idx)? {
+            0 => self.get(word(&raw?)?),
             1 => raw,
-            2 => self.get(raw? + self.relative_base),
-            n => Err(Error::UnknownOpmode { mode: n }),
+            2 => self.get(self.offset(word(&raw?)?, self.relative_base)?),
+            n => Err(ErrorKind::UnknownOpmode { mode: n }),
         }
     }
 
-    fn get_addr_arg(&self, idx: usize) -> Result<i64, Error> {
+    fn get_addr_arg(&self, idx: usize) -> Result<i64, ErrorKind> {
         let raw = self.get_arg_raw(idx);
-        match self.get_arg_mode(idx) {
-            0 => raw,
-            1 => Err(Error::InvalidOpmode { mode: 1 }),
-            2 => Ok(raw? + self.relative_base),
-            mode => Err(Error::UnknownOpmode { mode }),
+        match self.get_arg_mode(idx)? {
+            0 => word(&raw?),
+            1 => Err(ErrorKind::InvalidOpmode { mode: 1 }),
+            2 => self.offset(word(&raw?)?, self.relative_base),
+            mode => Err(ErrorKind::UnknownOpmode { mode }),
         }
     }
 }
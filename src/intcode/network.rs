@@ -0,0 +1,546 @@
+use super::{Error, ErrorKind, Machine};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::iter;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const POLL: Duration = Duration::from_millis(1);
+
+pub const NAT_ADDRESS: i64 = 255;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Until {
+    AllHalted,
+    Outputs { node: usize, count: usize },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct UnknownNode(pub usize);
+
+impl fmt::Display for UnknownNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "node {} does not exist", self.0)
+    }
+}
+
+impl std::error::Error for UnknownNode {}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PipeReport {
+    pub outputs: Vec<Vec<i64>>,
+    pub halted: Vec<bool>,
+}
+
+#[derive(Debug, Clone)]
+struct PipeNode {
+    machine: Machine,
+    prelude: Vec<i64>,
+    targets: Vec<usize>,
+    watched: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PipeNetwork {
+    nodes: Vec<PipeNode>,
+}
+
+#[derive(Default)]
+struct PipeState {
+    stop: AtomicBool,
+    running: AtomicUsize,
+    blocked: AtomicUsize,
+    in_flight: AtomicUsize,
+}
+
+impl PipeState {
+    fn send(&self, tx: &Sender<i64>, val: i64) {
+        self.in_flight.fetch_add(1, SeqCst);
+        if tx.send(val).is_err() {
+            self.in_flight.fetch_sub(1, SeqCst);
+        }
+    }
+
+    // Every node blocked on an empty channel with nothing in flight means the
+    // network can make no further progress, so the whole run is stopped.
+    fn recv(&self, rx: &Receiver<i64>) -> Option<i64> {
+        match rx.try_recv() {
+            Ok(val) => {
+                self.in_flight.fetch_sub(1, SeqCst);
+                return Some(val);
+            }
+            Err(TryRecvError::Disconnected) => return None,
+            Err(TryRecvError::Empty) => {}
+        }
+        self.blocked.fetch_add(1, SeqCst);
+        let ret = loop {
+            if self.stop.load(SeqCst) {
+                break None;
+            }
+            match rx.recv_timeout(POLL) {
+                Ok(val) => break Some(val),
+                Err(RecvTimeoutError::Disconnected) => break None,
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.blocked.load(SeqCst) == self.running.load(SeqCst)
+                        && self.in_flight.load(SeqCst) == 0
+                    {
+                        self.stop.store(true, SeqCst);
+                    }
+                }
+            }
+        };
+        self.blocked.fetch_sub(1, SeqCst);
+        if ret.is_some() {
+            self.in_flight.fetch_sub(1, SeqCst);
+        }
+        ret
+    }
+}
+
+// Wiring shared by `PipeNetwork` and the single-threaded `Pipeline` in
+// `algorithms::amplifier`. Watched nodes have their outputs collected in the
+// report.
+pub trait Topology: Default {
+    fn add_node(&mut self, machine: Machine, prelude: Vec<i64>) -> usize;
+
+    fn pipe(&mut self, from: usize, to: usize) -> Result<&mut Self, UnknownNode>;
+
+    fn watch(&mut self, node: usize) -> Result<&mut Self, UnknownNode>;
+
+    // Amplifiers in a row, each started with its phase and the first also
+    // with a zero signal. Only the last one is watched.
+    fn chain(machine: &Machine, phases: &[i64], feedback: bool) -> Self {
+        let mut ret = Self::default();
+        let nodes: Vec<_> = phases
+            .iter()
+            .enumerate()
+            .map(|(idx, &phase)| {
+                let prelude = if idx == 0 {
+                    vec![phase, 0]
+                } else {
+                    vec![phase]
+                };
+                ret.add_node(machine.clone(), prelude)
+            })
+            .collect();
+        let wire = |ret: &mut Self| -> Result<(), UnknownNode> {
+            for pair in nodes.windows(2) {
+                ret.pipe(pair[0], pair[1])?;
+            }
+            if let (Some(&first), Some(&last)) = (nodes.first(), nodes.last()) {
+                ret.watch(last)?;
+                if feedback {
+                    ret.pipe(last, first)?;
+                }
+            }
+            Ok(())
+        };
+        wire(&mut ret).expect("chain only wires nodes it added");
+        ret
+    }
+}
+
+impl Topology for PipeNetwork {
+    fn add_node(&mut self, machine: Machine, prelude: Vec<i64>) -> usize {
+        self.nodes.push(PipeNode {
+            machine,
+            prelude,
+            targets: Vec::new(),
+            watched: false,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn pipe(&mut self, from: usize, to: usize) -> Result<&mut Self, UnknownNode> {
+        if to >= self.nodes.len() {
+            return Err(UnknownNode(to));
+        }
+        let node = self.nodes.get_mut(from).ok_or(UnknownNode(from))?;
+        node.targets.push(to);
+        Ok(self)
+    }
+
+    fn watch(&mut self, node: usize) -> Result<&mut Self, UnknownNode> {
+        self.nodes.get_mut(node).ok_or(UnknownNode(node))?.watched = true;
+        Ok(self)
+    }
+}
+
+impl PipeNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn run(mut self, until: Until) -> Result<PipeReport, Error> {
+        let state = Arc::new(PipeState::default());
+        // A node in `until` that does not exist never produces output, so the
+        // run goes on until every node halts or blocks, as in `Pipeline`.
+        if let Until::Outputs { node, count } = until {
+            let _ = self.watch(node);
+            state.stop.store(count == 0, SeqCst);
+        }
+        state.running.store(self.nodes.len(), SeqCst);
+        let (senders, receivers): (Vec<_>, Vec<_>) =
+            self.nodes.iter().map(|_| mpsc::channel()).unzip();
+        let (watch_tx, watch_rx) = mpsc::channel();
+        let mut outputs = vec![Vec::new(); self.nodes.len()];
+
+        let handles: Vec<_> = self
+            .nodes
+            .into_iter()
+            .zip(receivers)
+            .enumerate()
+            .map(|(idx, (node, rx))| {
+                let state = Arc::clone(&state);
+                let targets: Vec<Sender<i64>> =
+                    node.targets.iter().map(|&t| senders[t].clone()).collect();
+                let watch_tx = Some(watch_tx.clone()).filter(|_| node.watched);
+                let PipeNode {
+                    mut machine,
+                    prelude,
+                    ..
+                } = node;
+                thread::spawn(move || {
+                    let mut input = prelude.into_iter().chain(iter::from_fn(|| state.recv(&rx)));
+                    // A node that never reads input only notices the stop when
+                    // it next outputs.
+                    let stopped = Cell::new(false);
+                    let mut output = |val| {
+                        if state.stop.load(SeqCst) {
+                            stopped.set(true);
+                            return;
+                        }
+                        for tx in &targets {
+                            state.send(tx, val);
+                        }
+                        if let Some(tx) = &watch_tx {
+                            let _ = tx.send((idx, val));
+                        }
+                    };
+                    let ret = loop {
+                        if machine.done || stopped.get() {
+                            break Ok(machine.done);
+                        }
+                        match machine.step_inner(&mut input, &mut output) {
+                            Ok(()) => {}
+                            Err(ErrorKind::Eof) => break Ok(false),
+                            Err(kind) => break Err(machine.locate(kind)),
+                        }
+                    };
+                    state.running.fetch_sub(1, SeqCst);
+                    ret
+                })
+            })
+            .collect();
+        drop(senders);
+        drop(watch_tx);
+
+        for (idx, val) in watch_rx {
+            match until {
+                Until::Outputs { node, count } if node == idx => {
+                    if outputs[idx].len() < count {
+                        outputs[idx].push(val);
+                    }
+                    if outputs[idx].len() == count {
+                        state.stop.store(true, SeqCst);
+                    }
+                }
+                _ => outputs[idx].push(val),
+            }
+        }
+        let halted = handles
+            .into_iter()
+            .map(|h| h.join().expect("intcode node panicked"))
+            .collect::<Result<_, _>>()?;
+        Ok(PipeReport { outputs, halted })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum NatCondition {
+    FirstPacket,
+    RepeatedY,
+}
+
+#[derive(Debug, Clone)]
+pub struct PacketNetwork {
+    machine: Machine,
+    size: usize,
+}
+
+struct PacketState {
+    stop: AtomicBool,
+    in_flight: AtomicUsize,
+    activity: AtomicUsize,
+    idle_polls: Vec<AtomicUsize>,
+}
+
+impl PacketState {
+    fn send(&self, tx: &Sender<(i64, i64)>, packet: (i64, i64)) {
+        self.in_flight.fetch_add(1, SeqCst);
+        self.activity.fetch_add(1, SeqCst);
+        if tx.send(packet).is_err() {
+            self.in_flight.fetch_sub(1, SeqCst);
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.in_flight.load(SeqCst) == 0 && self.idle_polls.iter().all(|n| n.load(SeqCst) >= 2)
+    }
+}
+
+impl PacketNetwork {
+    pub fn new(machine: &Machine, size: usize) -> Self {
+        PacketNetwork {
+            machine: machine.clone(),
+            size,
+        }
+    }
+
+    pub fn run(&self, until: NatCondition) -> Result<Option<(i64, i64)>, Error> {
+        let state = Arc::new(PacketState {
+            stop: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            activity: AtomicUsize::new(0),
+            idle_polls: (0..self.size).map(|_| AtomicUsize::new(0)).collect(),
+        });
+        let (senders, receivers): (Vec<_>, Vec<_>) =
+            (0..self.size).map(|_| mpsc::channel()).unzip();
+        let (nat_tx, nat_rx) = mpsc::channel();
+
+        let handles: Vec<_> = receivers
+            .into_iter()
+            .enumerate()
+            .map(|(addr, rx)| {
+                let state = Arc::clone(&state);
+                let senders = senders.clone();
+                let nat_tx = nat_tx.clone();
+                let mut machine = self.machine.clone();
+                thread::spawn(move || {
+                    let mut pending = VecDeque::from(vec![addr as i64]);
+                    let mut input = iter::from_fn(|| {
+                        if state.stop.load(SeqCst) {
+                            return None;
+                        }
+                        if let Some(val) = pending.pop_front() {
+                            return Some(val);
+                        }
+                        match rx.try_recv() {
+                            Ok((x, y)) => {
+                                state.idle_polls[addr].store(0, SeqCst);
+                                state.in_flight.fetch_sub(1, SeqCst);
+                                pending.push_back(y);
+                                Some(x)
+                            }
+                            Err(_) => {
+                                state.idle_polls[addr].fetch_add(1, SeqCst);
+                                thread::yield_now();
+                                Some(-1)
+                            }
+                        }
+                    });
+                    let mut packet = Vec::with_capacity(3);
+                    let mut output = |val| {
+                        packet.push(val);
+                        if packet.len() < 3 {
+                            return;
+                        }
+                        state.idle_polls[addr].store(0, SeqCst);
+                        let (dest, x, y) = (packet[0], packet[1], packet[2]);
+                        packet.clear();
+                        if dest == NAT_ADDRESS {
+                            state.activity.fetch_add(1, SeqCst);
+                            let _ = nat_tx.send((x, y));
+                        } else if let Some(tx) = senders.get(dest as usize) {
+                            state.send(tx, (x, y));
+                        }
+                    };
+                    machine.run_io(&mut input, &mut output)
+                })
+            })
+            .collect();
+        drop(nat_tx);
+
+        let mut ret = None;
+        let mut nat = None;
+        let mut last_sent_y = None;
+        let mut idle_since = None;
+        loop {
+            match nat_rx.recv_timeout(POLL) {
+                Ok(packet) if until == NatCondition::FirstPacket => {
+                    ret = Some(packet);
+                    break;
+                }
+                Ok(packet) => nat = Some(packet),
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            if !state.is_idle() {
+                idle_since = None;
+                continue;
+            }
+            let activity = state.activity.load(SeqCst);
+            if idle_since != Some(activity) {
+                idle_since = Some(activity);
+                continue;
+            }
+            if let Some(packet) = nat {
+                if last_sent_y == Some(packet.1) {
+                    ret = Some(packet);
+                    break;
+                }
+                last_sent_y = Some(packet.1);
+                state.send(&senders[0], packet);
+                idle_since = None;
+            }
+        }
+        state.stop.store(true, SeqCst);
+        drop(senders);
+        for handle in handles {
+            handle.join().expect("intcode node panicked")?;
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::*;
+    use crate::algorithms::amplifier;
+
+    const FEEDBACK_PROG: [i64; 29] = [
+        3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1, 28,
+        1005, 28, 6, 99, 0, 0, 5,
+    ];
+
+    #[test]
+    fn network_linear_chain() {
+        let machine = Machine::new(vec![3, 0, 3, 1, 1, 0, 1, 0, 4, 0, 99]);
+        let report = PipeNetwork::chain(&machine, &[1, 2, 3, 4], false)
+            .run(Until::AllHalted)
+            .unwrap();
+        assert_eq!(report.outputs[3], &[10]);
+        assert_eq!(
+            Some(10),
+            amplifier::score_setting(&machine, vec![1, 2, 3, 4]).unwrap()
+        );
+        assert!(report.halted.iter().all(|&h| h));
+    }
+
+    #[test]
+    fn network_feedback_ring() {
+        let machine = Machine::new(FEEDBACK_PROG.to_vec());
+        let report = PipeNetwork::chain(&machine, &[9, 8, 7, 6, 5], true)
+            .run(Until::AllHalted)
+            .unwrap();
+        assert_eq!(report.outputs[4].last(), Some(&139629729));
+    }
+
+    #[test]
+    fn network_stops_on_output_count() {
+        let machine = Machine::new(
+            assemble("loop: in [x]\nadd [x], #1, [x]\nout [x]\njt #1, #loop\nx: .data 0").unwrap(),
+        );
+        let mut net = PipeNetwork::new();
+        let a = net.add_node(machine.clone(), vec![0]);
+        let b = net.add_node(machine, vec![]);
+        net.pipe(a, b)
+            .unwrap()
+            .pipe(b, a)
+            .unwrap()
+            .watch(b)
+            .unwrap();
+        let report = net.run(Until::Outputs { node: b, count: 3 }).unwrap();
+        assert_eq!(report.outputs[b], &[2, 4, 6]);
+        assert_eq!(report.halted, &[false, false]);
+    }
+
+    #[test]
+    fn network_stops_nodes_that_only_output() {
+        let mut net = PipeNetwork::new();
+        let a = net.add_node(
+            Machine::new(assemble("loop: out #1\njt #1, #loop").unwrap()),
+            vec![],
+        );
+        let report = net.run(Until::Outputs { node: a, count: 3 }).unwrap();
+        assert_eq!(report.outputs[a], &[1, 1, 1]);
+        assert_eq!(report.halted, &[false]);
+    }
+
+    #[test]
+    fn network_detects_deadlock() {
+        let mut net = PipeNetwork::new();
+        let a = net.add_node(Machine::new(vec![3, 0, 4, 0, 99]), vec![]);
+        let b = net.add_node(Machine::new(vec![3, 0, 4, 0, 99]), vec![]);
+        net.pipe(a, b).unwrap().pipe(b, a).unwrap();
+        assert_eq!(net.run(Until::AllHalted).unwrap().halted, &[false, false]);
+    }
+
+    #[test]
+    fn network_rejects_unknown_nodes() {
+        let mut net = PipeNetwork::new();
+        let a = net.add_node(Machine::new(vec![104, 1, 99]), vec![]);
+        assert_eq!(net.pipe(a, 1).unwrap_err(), UnknownNode(1));
+        assert_eq!(net.pipe(2, a).unwrap_err(), UnknownNode(2));
+        assert_eq!(
+            net.watch(3).unwrap_err().to_string(),
+            "node 3 does not exist"
+        );
+        let report = net.run(Until::Outputs { node: 5, count: 1 }).unwrap();
+        assert_eq!(report.outputs, &[Vec::<i64>::new()]);
+        assert_eq!(report.halted, &[true]);
+    }
+
+    const FORWARDER: &str = "
+              in [addr]
+              jt [addr], #loop
+              out #1
+              out #0
+              out #7
+        loop: in [x]
+              eq [x], #-1, [tmp]
+              jt [tmp], #loop
+              in [y]
+              add [addr], #1, [dest]
+              eq [dest], #50, [tmp]
+              jf [tmp], #send
+              add #255, #0, [dest]
+        send: out [dest]
+              add [x], #1, [x]
+              out [x]
+              out [y]
+              jt #1, #loop
+        addr: .data 0
+        x:    .data 0
+        y:    .data 0
+        dest: .data 0
+        tmp:  .data 0
+    ";
+
+    #[test]
+    fn network_packets_first_nat() {
+        let machine = Machine::new(assemble(FORWARDER).unwrap());
+        assert_eq!(
+            PacketNetwork::new(&machine, 50)
+                .run(NatCondition::FirstPacket)
+                .unwrap(),
+            Some((49, 7))
+        );
+    }
+
+    #[test]
+    fn network_packets_nat_repeat() {
+        let machine = Machine::new(assemble(FORWARDER).unwrap());
+        assert_eq!(
+            PacketNetwork::new(&machine, 50)
+                .run(NatCondition::RepeatedY)
+                .unwrap(),
+            Some((99, 7))
+        );
+    }
+}
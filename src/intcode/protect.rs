@@ -0,0 +1,360 @@
+use super::cell::Cell;
+use super::disasm::Op;
+use super::{ErrorKind, Machine};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Permission {
+    ReadOnly,
+    ExecuteOnly,
+    Data,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+impl Permission {
+    pub fn allows(self, access: Access) -> bool {
+        matches!(
+            (self, access),
+            (Permission::ReadOnly, Access::Read)
+                | (Permission::ExecuteOnly, Access::Execute)
+                | (Permission::Data, Access::Read)
+                | (Permission::Data, Access::Write)
+        )
+    }
+}
+
+// What to do when a cell that has already been executed is written to.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum CodeWritePolicy {
+    #[default]
+    Allow,
+    Warn,
+    Deny,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CodeWrite {
+    pub ip: usize,
+    pub addr: usize,
+    pub executed: u64,
+}
+
+impl fmt::Display for CodeWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "instruction at address {} overwrote executed code at address {} after {} instructions",
+            self.ip, self.addr, self.executed
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Protection {
+    regions: Vec<(Range<usize>, Permission)>,
+    executed: Vec<u64>,
+    policy: CodeWritePolicy,
+    warnings: Vec<CodeWrite>,
+    first_write: Option<CodeWrite>,
+    modified: BTreeMap<usize, CodeWrite>,
+}
+
+impl Protection {
+    // Later regions take precedence where they overlap earlier ones; cells
+    // outside every region are unrestricted.
+    pub fn permission(&self, addr: usize) -> Option<Permission> {
+        self.regions
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&addr))
+            .map(|&(_, permission)| permission)
+    }
+
+    pub fn allows(&self, addr: usize, access: Access) -> bool {
+        self.permission(addr)
+            .is_none_or(|permission| permission.allows(access))
+    }
+
+    pub fn policy(&self) -> CodeWritePolicy {
+        self.policy
+    }
+
+    pub fn was_executed(&self, addr: usize) -> bool {
+        self.executed
+            .get(addr / 64)
+            .is_some_and(|word| word & (1 << (addr % 64)) != 0)
+    }
+
+    pub fn executed(&self) -> impl Iterator<Item = usize> + '_ {
+        self.executed.iter().enumerate().flat_map(|(idx, &word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| idx * 64 + bit)
+        })
+    }
+
+    pub fn first_code_write(&self) -> Option<CodeWrite> {
+        self.first_write
+    }
+
+    // The first write to each executed cell that was later overwritten.
+    pub fn modified_code(&self) -> impl Iterator<Item = &CodeWrite> {
+        self.modified.values()
+    }
+
+    pub fn warnings(&self) -> &[CodeWrite] {
+        &self.warnings
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<CodeWrite> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    // Only tracks executed cells, with no regions and nothing reported, for
+    // replaying steps that already passed every check.
+    pub(super) fn replaying(executed: Vec<u64>) -> Self {
+        Protection {
+            executed,
+            ..Protection::default()
+        }
+    }
+
+    pub(super) fn executed_bits(&self) -> &[u64] {
+        &self.executed
+    }
+
+    // Returns whether the cell had not been executed before.
+    fn mark_executed(&mut self, addr: usize) -> bool {
+        if addr / 64 >= self.executed.len() {
+            self.executed.resize(addr / 64 + 1, 0);
+        }
+        let bit = 1 << (addr % 64);
+        let new = self.executed[addr / 64] & bit == 0;
+        self.executed[addr / 64] |= bit;
+        new
+    }
+
+    pub(super) fn unmark_executed(&mut self, addr: usize) {
+        if let Some(word) = self.executed.get_mut(addr / 64) {
+            *word &= !(1 << (addr % 64));
+        }
+    }
+}
+
+impl<C: Cell> Machine<C> {
+    pub fn protect(&mut self, range: Range<usize>, permission: Permission) {
+        self.protection_mut().regions.push((range, permission));
+    }
+
+    pub fn set_code_write_policy(&mut self, policy: CodeWritePolicy) {
+        self.protection_mut().policy = policy;
+    }
+
+    pub fn protection(&self) -> Option<&Protection> {
+        self.protection.as_deref()
+    }
+
+    pub fn take_code_write_warnings(&mut self) -> Vec<CodeWrite> {
+        self.protection
+            .as_mut()
+            .map_or_else(Vec::new, |protection| protection.take_warnings())
+    }
+
+    fn protection_mut(&mut self) -> &mut Protection {
+        self.protection.get_or_insert_with(Box::default)
+    }
+
+    pub(super) fn check_access(&self, addr: i64, access: Access) -> Result<(), ErrorKind> {
+        match &self.protection {
+            Some(protection) if addr >= 0 && !protection.allows(addr as usize, access) => {
+                Err(ErrorKind::Protected { addr, access })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Checks that the whole instruction at `cur` may be executed and marks it
+    // as executed, operands included.
+    pub(super) fn check_execute(&mut self, word: i64) -> Result<(), ErrorKind> {
+        if self.protection.is_none() {
+            return Ok(());
+        }
+        let opcode = word % 100;
+        let params = match Op::from_opcode(opcode) {
+            Some(op) => op.params().len(),
+            None => self
+                .extensions
+                .as_ref()
+                .and_then(|registry| registry.get(opcode))
+                .map_or(0, |ext| ext.params.len()),
+        };
+        let range = self.cur..self.cur + 1 + params;
+        for addr in range.clone() {
+            self.check_access(addr as i64, Access::Execute)?;
+        }
+        if let Some(protection) = &mut self.protection {
+            for addr in range.filter(|&addr| protection.mark_executed(addr)) {
+                if let Some(journal) = &mut self.journal {
+                    journal.record_executed(addr);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn check_code_write(&mut self, addr: usize) -> Result<(), ErrorKind> {
+        let (ip, executed) = (self.cur, self.executed);
+        let protection = match &mut self.protection {
+            Some(protection) if protection.was_executed(addr) => protection,
+            _ => return Ok(()),
+        };
+        let write = CodeWrite { ip, addr, executed };
+        match protection.policy {
+            CodeWritePolicy::Deny => return Err(ErrorKind::ModifiedCode { addr }),
+            CodeWritePolicy::Warn => protection.warnings.push(write),
+            CodeWritePolicy::Allow => {}
+        }
+        protection.first_write.get_or_insert(write);
+        protection.modified.entry(addr).or_insert(write);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::super::fast::FastMachine;
+    use super::super::Error;
+    use super::*;
+    use std::iter;
+
+    // Counts down by patching the immediate of its own `add`.
+    const PATCHING: &str = "
+        loop:   out [n]
+                add #3, #-1, [n]
+        patch:  add [loop+3], #-1, [loop+3]
+                jt [n], #loop
+                end
+        n:      .data 9
+    ";
+
+    fn run(machine: &mut Machine) -> Result<Vec<i64>, Error> {
+        machine.run_to_end(iter::empty())
+    }
+
+    #[test]
+    fn protect_read_only_region() {
+        let mut machine = Machine::new(vec![1101, 1, 2, 6, 99, 0, 0]);
+        machine.protect(5..7, Permission::ReadOnly);
+        let err = run(&mut machine).unwrap_err();
+        assert_eq!(
+            (err.kind.clone(), err.ip),
+            (
+                ErrorKind::Protected {
+                    addr: 6,
+                    access: Access::Write
+                },
+                0
+            )
+        );
+        assert_eq!(
+            err.to_string().lines().next(),
+            Some("write access to protected address 6 at address 0 (instruction 1101, relative base 0)")
+        );
+    }
+
+    #[test]
+    fn protect_execute_only_and_data() {
+        // Reading its own code as data faults, running it does not.
+        let code = assemble("out [0]\nend").unwrap();
+        let mut machine = Machine::new(code.clone());
+        machine.protect(0..3, Permission::ExecuteOnly);
+        assert_eq!(
+            run(&mut machine).unwrap_err().kind,
+            ErrorKind::Protected {
+                addr: 0,
+                access: Access::Read
+            }
+        );
+        let mut machine = Machine::new(code);
+        machine.protect(0..3, Permission::ExecuteOnly);
+        machine.protect(0..1, Permission::ReadOnly);
+        assert_eq!(
+            run(&mut machine).unwrap_err().kind,
+            ErrorKind::Protected {
+                addr: 0,
+                access: Access::Execute
+            }
+        );
+
+        let mut machine = Machine::new(assemble("jt #1, #x\nx: .data 99").unwrap());
+        machine.protect(3..4, Permission::Data);
+        let err = run(&mut machine).unwrap_err();
+        assert_eq!((err.ip, err.instruction), (3, 99));
+    }
+
+    #[test]
+    fn protect_code_writes() {
+        let code = assemble(PATCHING).unwrap();
+        let mut machine = Machine::new(code.clone());
+        machine.set_code_write_policy(CodeWritePolicy::Allow);
+        assert_eq!(run(&mut machine).unwrap(), &[9, 2, 1]);
+        let protection = machine.protection().unwrap();
+        assert!(protection.warnings().is_empty());
+        let first = CodeWrite {
+            ip: 6,
+            addr: 3,
+            executed: 2,
+        };
+        assert_eq!(protection.first_code_write(), Some(first));
+        assert_eq!(
+            protection.modified_code().copied().collect::<Vec<_>>(),
+            &[first]
+        );
+        assert_eq!(
+            protection.executed().collect::<Vec<_>>(),
+            (0..14).collect::<Vec<_>>()
+        );
+
+        let mut machine = Machine::new(code.clone());
+        machine.set_code_write_policy(CodeWritePolicy::Warn);
+        run(&mut machine).unwrap();
+        assert_eq!(machine.take_code_write_warnings().len(), 3);
+        assert!(machine.take_code_write_warnings().is_empty());
+
+        let mut machine = Machine::new(code);
+        machine.set_code_write_policy(CodeWritePolicy::Deny);
+        let err = run(&mut machine).unwrap_err();
+        assert_eq!((err.kind, err.ip), (ErrorKind::ModifiedCode { addr: 3 }, 6));
+        assert_eq!(machine.memory()[3], 3);
+    }
+
+    #[test]
+    fn protect_fast_machine() {
+        let mut machine = Machine::new(assemble(PATCHING).unwrap());
+        machine.set_code_write_policy(CodeWritePolicy::Deny);
+        let mut fast = FastMachine::from(machine.clone());
+        assert_eq!(
+            fast.run_to_end(iter::empty()).unwrap_err(),
+            run(&mut machine).unwrap_err()
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use super::fast::FastMachine;
+use super::memory::Memory;
+use super::{Budget, Error, ErrorKind, Machine, Status};
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::thread;
+
+pub const DEFAULT_MAX_INSTRUCTIONS: u64 = 1_000_000;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Slot {
+    Memory(usize),
+    Input(usize),
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Patch {
+    pub values: Vec<(Slot, i64)>,
+}
+
+impl Patch {
+    pub fn get(&self, slot: Slot) -> Option<i64> {
+        self.values
+            .iter()
+            .find(|&&(s, _)| s == slot)
+            .map(|&(_, val)| val)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub patch: Patch,
+    pub result: Result<Status, Error>,
+    pub output: Vec<i64>,
+    pub machine: Machine,
+}
+
+impl Outcome {
+    pub fn memory(&self) -> &Memory {
+        self.machine.memory()
+    }
+}
+
+// Tries every combination of the values given for each slot, in order with
+// the last slot varying fastest, on a copy of the base machine.
+#[derive(Debug, Clone)]
+pub struct Search {
+    machine: Machine,
+    input: Vec<i64>,
+    axes: Vec<(Slot, Vec<i64>)>,
+    budget: Budget,
+    threads: usize,
+}
+
+impl Search {
+    pub fn new(machine: Machine) -> Self {
+        Search {
+            machine,
+            input: Vec::new(),
+            axes: Vec::new(),
+            budget: Budget::instructions(DEFAULT_MAX_INSTRUCTIONS),
+            threads: 1,
+        }
+    }
+
+    pub fn input(&mut self, input: Vec<i64>) -> &mut Self {
+        self.input = input;
+        self
+    }
+
+    pub fn vary<I: IntoIterator<Item = i64>>(&mut self, slot: Slot, values: I) -> &mut Self {
+        self.axes.push((slot, values.into_iter().collect()));
+        self
+    }
+
+    pub fn budget(&mut self, budget: Budget) -> &mut Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.axes.iter().map(|(_, values)| values.len()).product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn patch(&self, mut idx: usize) -> Patch {
+        let mut values = Vec::with_capacity(self.axes.len());
+        for (slot, vals) in self.axes.iter().rev() {
+            values.push((*slot, vals[idx % vals.len()]));
+            idx /= vals.len();
+        }
+        values.reverse();
+        Patch { values }
+    }
+
+    pub fn try_patch(&self, patch: Patch) -> Outcome {
+        let mut machine = self.machine.clone();
+        machine.set_budget(self.budget);
+        let mut input = self.input.clone();
+        for &(slot, val) in &patch.values {
+            match slot {
+                Slot::Memory(addr) => {
+                    if machine.code.set(addr, val).is_none() {
+                        let err = machine.locate(ErrorKind::OutOfBounds { addr: addr as i64 });
+                        return Outcome {
+                            patch,
+                            result: Err(err),
+                            output: Vec::new(),
+                            machine,
+                        };
+                    }
+                }
+                Slot::Input(idx) => {
+                    if idx >= input.len() {
+                        input.resize(idx + 1, 0);
+                    }
+                    input[idx] = val;
+                }
+            }
+        }
+        let mut fast = FastMachine::from(machine);
+        let mut output = Vec::new();
+        let result = fast.run_io(&mut input.into_iter(), &mut output);
+        Outcome {
+            patch,
+            result,
+            output,
+            machine: fast.into_machine(),
+        }
+    }
+
+    // Every outcome accepted by `pred`, in search order.
+    pub fn run<F>(&self, pred: F) -> Vec<Outcome>
+    where
+        F: Fn(&Outcome) -> bool + Sync,
+    {
+        self.search(&pred, false)
+    }
+
+    pub fn find<F>(&self, pred: F) -> Option<Outcome>
+    where
+        F: Fn(&Outcome) -> bool + Sync,
+    {
+        self.search(&pred, true).into_iter().next()
+    }
+
+    // Threads take every `threads`th candidate. When only the first match is
+    // wanted, candidates after the earliest match found so far are skipped.
+    fn search<F>(&self, pred: &F, first: bool) -> Vec<Outcome>
+    where
+        F: Fn(&Outcome) -> bool + Sync,
+    {
+        let len = self.len();
+        let threads = self.threads.min(len).max(1);
+        let best = AtomicUsize::new(usize::MAX);
+        let worker = |start: usize| {
+            let mut found = Vec::new();
+            for idx in (start..len).step_by(threads) {
+                if idx > best.load(SeqCst) {
+                    break;
+                }
+                let outcome = self.try_patch(self.patch(idx));
+                if pred(&outcome) {
+                    found.push((idx, outcome));
+                    if first {
+                        best.fetch_min(idx, SeqCst);
+                        break;
+                    }
+                }
+            }
+            found
+        };
+        let mut found = if threads == 1 {
+            worker(0)
+        } else {
+            thread::scope(|scope| {
+                let handles: Vec<_> = (0..threads)
+                    .map(|start| scope.spawn(move || worker(start)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap())
+                    .collect()
+            })
+        };
+        found.sort_by_key(|&(idx, _)| idx);
+        if first {
+            found.truncate(1);
+        }
+        found.into_iter().map(|(_, outcome)| outcome).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::*;
+
+    // Stores noun * 100 + verb at address 0, like the gravity assist program.
+    const GRAVITY: [i64; 9] = [1102, 0, 100, 0, 1001, 0, 0, 0, 99];
+
+    fn gravity() -> Search {
+        let mut search = Search::new(Machine::new(GRAVITY.to_vec()));
+        search
+            .vary(Slot::Memory(1), 0..100)
+            .vary(Slot::Memory(6), 0..100);
+        search
+    }
+
+    #[test]
+    fn search_memory_patches() {
+        let search = gravity();
+        assert_eq!(search.len(), 10_000);
+        assert_eq!(
+            search.patch(1234).values,
+            &[(Slot::Memory(1), 12), (Slot::Memory(6), 34)]
+        );
+        let found = search.run(|outcome| outcome.memory()[0] == 1234);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].patch, search.patch(1234));
+        assert_eq!(found[0].result, Ok(Status::Halted));
+
+        let found = search.find(|outcome| outcome.memory()[0] % 1000 == 999);
+        assert_eq!(found.unwrap().patch.get(Slot::Memory(1)), Some(9));
+    }
+
+    #[test]
+    fn search_in_parallel() {
+        let mut search = gravity();
+        let pred = |outcome: &Outcome| outcome.memory()[0] % 97 == 0;
+        let expected: Vec<_> = search.run(pred).into_iter().map(|o| o.patch).collect();
+        search.threads(4);
+        let found: Vec<_> = search.run(pred).into_iter().map(|o| o.patch).collect();
+        assert_eq!(found, expected);
+        assert_eq!(search.find(pred).unwrap().patch, expected[0]);
+        assert_eq!(search.threads(64).find(|_| false).map(|o| o.patch), None);
+    }
+
+    #[test]
+    fn search_inputs_and_limits() {
+        // Loops forever unless the second input is positive.
+        let code = assemble(
+            "
+                    in [x]
+                    in [y]
+            loop:   jf [y], #loop
+                    mul [x], [y], [x]
+                    out [x]
+                    end
+            x:      .data 0
+            y:      .data 0
+            ",
+        )
+        .unwrap();
+        let mut search = Search::new(Machine::new(code));
+        search
+            .input(vec![0, 0])
+            .vary(Slot::Input(0), 1..=3)
+            .vary(Slot::Input(1), 0..=2)
+            .budget(Budget::instructions(50));
+        let found = search.run(|outcome| outcome.output == [2]);
+        assert_eq!(
+            found.iter().map(|o| o.patch.clone()).collect::<Vec<_>>(),
+            &[search.patch(2), search.patch(4)]
+        );
+
+        let stuck = search.run(|outcome| outcome.result.is_err());
+        assert_eq!(stuck.len(), 3);
+        assert!(stuck
+            .iter()
+            .all(|outcome| outcome.result.as_ref().unwrap_err().kind
+                == ErrorKind::InstructionLimit { executed: 50 }));
+
+        let mut search = Search::new(Machine::with_memory_limit(vec![99], 4));
+        search.vary(Slot::Memory(8), vec![1]);
+        let outcome = search.try_patch(search.patch(0));
+        assert_eq!(outcome.result.unwrap_err().address(), Some(8));
+    }
+}
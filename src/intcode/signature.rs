@@ -0,0 +1,308 @@
+use super::cfg::{Cfg, Function, Terminator};
+use super::disasm::{Listing, Mode, Op, Param};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+// What a function expects from and leaves in its caller's frame. Slots are
+// offsets from the relative base at the call: slot 0 holds the return address
+// and arguments follow from slot 1. The callee allocates its frame with
+// `mrb #frame` so the caller's slots appear at negative offsets inside it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Signature {
+    pub entry: usize,
+    pub frame: Option<i64>,
+    pub args: usize,
+    pub returns: Vec<i64>,
+    pub callees: BTreeSet<usize>,
+    pub balanced: bool,
+}
+
+impl Signature {
+    pub fn is_recursive(&self) -> bool {
+        self.callees.contains(&self.entry)
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = match self.frame {
+            Some(frame) => frame,
+            None => return write!(f, "fn_{}: unknown frame layout", self.entry),
+        };
+        write!(f, "fn_{}: {} args, returns ", self.entry, self.args)?;
+        if self.returns.is_empty() {
+            write!(f, "nothing")?;
+        }
+        for (idx, slot) in self.returns.iter().enumerate() {
+            write!(f, "{}[rb+{}]", if idx == 0 { "" } else { ", " }, slot)?;
+        }
+        write!(f, ", frame {}", frame)?;
+        if self.is_recursive() {
+            write!(f, ", recursive")?;
+        }
+        if !self.balanced {
+            write!(f, ", unbalanced")?;
+        }
+        Ok(())
+    }
+}
+
+// Per-block abstract state: the relative base as an offset from its value on
+// entry, and the frame slots written on every path to the block.
+#[derive(Debug, Clone)]
+struct State {
+    delta: i64,
+    written: BTreeSet<i64>,
+}
+
+pub fn infer(cfg: &Cfg) -> BTreeMap<usize, Signature> {
+    cfg.functions
+        .values()
+        .map(|function| (function.entry, infer_function(cfg, function)))
+        .collect()
+}
+
+fn infer_function(cfg: &Cfg, function: &Function) -> Signature {
+    let mut sig = Signature {
+        entry: function.entry,
+        frame: Some(0),
+        args: 0,
+        returns: Vec::new(),
+        callees: BTreeSet::new(),
+        balanced: true,
+    };
+    let mut states = BTreeMap::new();
+    states.insert(
+        function.entry,
+        State {
+            delta: 0,
+            written: BTreeSet::new(),
+        },
+    );
+    let mut exposed = BTreeSet::new();
+    let mut written_any = BTreeSet::new();
+    let mut work = vec![function.entry];
+    while let Some(start) = work.pop() {
+        let block = &cfg.blocks[&start];
+        let mut state = states[&start].clone();
+        for (_, inst) in &block.instructions {
+            let args = inst.operands();
+            for (param, arg) in inst.op.params().iter().zip(args) {
+                let slot = state.delta + arg.value;
+                if arg.mode == Mode::Relative
+                    && *param == Param::Val
+                    && !state.written.contains(&slot)
+                {
+                    exposed.insert(slot);
+                }
+            }
+            for (param, arg) in inst.op.params().iter().zip(args) {
+                if arg.mode == Mode::Relative && *param == Param::Addr {
+                    state.written.insert(state.delta + arg.value);
+                    written_any.insert(state.delta + arg.value);
+                }
+            }
+            if inst.op == Op::Mrb {
+                match args[0].mode {
+                    Mode::Immediate => state.delta += args[0].value,
+                    _ => sig.frame = None,
+                }
+                sig.frame = sig.frame.map(|frame| frame.max(state.delta));
+            }
+        }
+        let successors = match block.terminator {
+            Terminator::Call { target, ret } => {
+                sig.callees.insert(target);
+                vec![ret]
+            }
+            Terminator::Return => {
+                sig.balanced &= state.delta == 0;
+                vec![]
+            }
+            terminator => terminator.successors(),
+        };
+        for next in successors
+            .into_iter()
+            .filter(|b| function.blocks.contains(b))
+        {
+            match states.get_mut(&next) {
+                None => {
+                    states.insert(next, state.clone());
+                    work.push(next);
+                }
+                Some(old) => {
+                    if old.delta != state.delta {
+                        sig.frame = None;
+                    }
+                    let len = old.written.len();
+                    old.written.retain(|slot| state.written.contains(slot));
+                    if old.written.len() < len {
+                        work.push(next);
+                    }
+                }
+            }
+        }
+    }
+
+    let frame = match sig.frame {
+        Some(frame) => frame,
+        None => return sig,
+    };
+    sig.args = exposed
+        .range(1..frame)
+        .next_back()
+        .map_or(0, |&slot| slot as usize);
+    let observed = caller_reads(cfg, function.entry);
+    sig.returns = written_any
+        .range(1..frame)
+        .copied()
+        .filter(|slot| observed.contains(slot))
+        .collect();
+    sig
+}
+
+// Slots that callers read back right after the call returns, before writing
+// them or moving the relative base.
+fn caller_reads(cfg: &Cfg, entry: usize) -> BTreeSet<i64> {
+    let mut ret = BTreeSet::new();
+    for block in cfg.blocks.values() {
+        let after = match block.terminator {
+            Terminator::Call { target, ret } if target == entry => ret,
+            _ => continue,
+        };
+        let mut written = BTreeSet::new();
+        let insts = cfg
+            .blocks
+            .get(&after)
+            .map_or(&[][..], |b| &b.instructions[..]);
+        for (_, inst) in insts {
+            if inst.op == Op::Mrb {
+                break;
+            }
+            for (param, arg) in inst.op.params().iter().zip(inst.operands()) {
+                if arg.mode != Mode::Relative || arg.value < 1 {
+                    continue;
+                }
+                match param {
+                    Param::Val if !written.contains(&arg.value) => {
+                        ret.insert(arg.value);
+                    }
+                    Param::Addr => {
+                        written.insert(arg.value);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    ret
+}
+
+pub fn label(listing: &mut Listing, signatures: &BTreeMap<usize, Signature>) {
+    for sig in signatures.values() {
+        listing.annotate(sig.entry, sig.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::super::disasm::disassemble;
+    use super::super::Machine;
+    use super::*;
+
+    // fact(n) keeps n in its first argument slot and overwrites it with the
+    // result; sum(a, b) returns in the first slot and leaves the second alone.
+    const PROGRAM: &str = "
+                mrb #stack
+                add #5, #0, [rb+1]
+                add #r1, #0, [rb+0]
+                jt #1, #fact
+        r1:     out [rb+1]
+                add #20, #0, [rb+1]
+                add #22, #0, [rb+2]
+                add #r2, #0, [rb+0]
+                jt #1, #sum
+        r2:     out [rb+1]
+                end
+
+        fact:   mrb #3
+                lt [rb-2], #2, [rb-1]
+                jt [rb-1], #base
+                add [rb-2], #-1, [rb+1]
+                add #back, #0, [rb+0]
+                jt #1, #fact
+        back:   mul [rb-2], [rb+1], [rb-2]
+                jt #1, #done
+        base:   add #1, #0, [rb-2]
+        done:   mrb #-3
+                jt #1, [rb+0]
+
+        sum:    mrb #3
+                add [rb-2], [rb-1], [rb-2]
+                mrb #-3
+                jf #0, [rb+0]
+        stack:  .data 0
+    ";
+
+    #[test]
+    fn signature_program_runs() {
+        let code = assemble(PROGRAM).unwrap();
+        assert_eq!(
+            Machine::new(code).run_to_end(std::iter::empty()).unwrap(),
+            &[120, 42]
+        );
+    }
+
+    #[test]
+    fn signature_inference() {
+        let code = assemble(PROGRAM).unwrap();
+        let sigs = infer(&Cfg::new(&code));
+        let entries: Vec<_> = sigs.keys().copied().collect();
+        assert_eq!(entries, &[33, 69]);
+
+        let fact = &sigs[&33];
+        assert_eq!(fact.frame, Some(3));
+        assert_eq!(fact.args, 1);
+        assert_eq!(fact.returns, &[1]);
+        assert!(fact.is_recursive() && fact.balanced);
+        assert_eq!(
+            fact.to_string(),
+            "fn_33: 1 args, returns [rb+1], frame 3, recursive"
+        );
+
+        let sum = &sigs[&69];
+        assert_eq!((sum.args, &sum.returns[..]), (2, &[1][..]));
+        assert!(!sum.is_recursive());
+    }
+
+    #[test]
+    fn signature_unknown_frame() {
+        let code = assemble(
+            "
+                  add #r, #0, [rb+0]
+                  jt #1, #f
+            r:    end
+            f:    mrb [x]
+                  jt #1, [rb+0]
+            x:    .data 0
+            ",
+        )
+        .unwrap();
+        let sigs = infer(&Cfg::new(&code));
+        assert_eq!(sigs[&8].frame, None);
+        assert_eq!(sigs[&8].to_string(), "fn_8: unknown frame layout");
+    }
+
+    #[test]
+    fn signature_labels_listing() {
+        let code = assemble(PROGRAM).unwrap();
+        let mut listing = disassemble(&code);
+        label(&mut listing, &infer(&Cfg::new(&code)));
+        let text = listing.to_string();
+        assert!(text.contains(
+            "   32: end\n       ; fn_33: 1 args, returns [rb+1], frame 3, recursive\n   33: mrb #3\n"
+        ));
+        assert!(text.contains("       ; fn_69: 2 args, returns [rb+1], frame 3\n   69: mrb #3\n"));
+    }
+}
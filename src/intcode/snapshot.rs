@@ -0,0 +1,474 @@
+use super::cell::Arithmetic;
+use super::memory::{self, Memory};
+use super::{Budget, Machine};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+// Version 1 lacked `arithmetic`, `exit_code` and the budget; such files still
+// load with the defaults.
+pub const VERSION: u32 = 2;
+
+const MAGIC: &str = "intcode-snapshot";
+// Zero gaps shorter than this are written inline instead of starting a new run.
+const MAX_GAP: usize = 8;
+
+// Extensions, the undo journal and protection state are not saved; restoring
+// a machine that used them needs them set up again.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub cur: usize,
+    pub relative_base: i64,
+    pub done: bool,
+    pub executed: u64,
+    pub input: Vec<i64>,
+    pub memory: Memory,
+    pub arithmetic: Arithmetic,
+    pub exit_code: Option<i64>,
+    // The instruction limit is what was left of it when the snapshot was
+    // taken; a timeout starts over when the snapshot is restored.
+    pub budget: Budget,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Change {
+    Cur(usize, usize),
+    RelativeBase(i64, i64),
+    Done(bool, bool),
+    Len(usize, usize),
+    Memory { addr: usize, old: i64, new: i64 },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Cur(old, new) => write!(f, "cur: {} -> {}", old, new),
+            Change::RelativeBase(old, new) => write!(f, "relative_base: {} -> {}", old, new),
+            Change::Done(old, new) => write!(f, "done: {} -> {}", old, new),
+            Change::Len(old, new) => write!(f, "len: {} -> {}", old, new),
+            Change::Memory { addr, old, new } => write!(f, "[{}]: {} -> {}", addr, old, new),
+        }
+    }
+}
+
+fn invalid(line: usize, msg: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("snapshot line {}: {}", line, msg),
+    )
+}
+
+fn parse<T: std::str::FromStr>(line: usize, word: Option<&str>) -> io::Result<T> {
+    let word = word.ok_or_else(|| invalid(line, "missing value"))?;
+    word.parse()
+        .map_err(|_| invalid(line, format!("invalid value '{}'", word)))
+}
+
+impl Snapshot {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write(&mut out)?;
+        out.flush()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{} {}", MAGIC, VERSION)?;
+        writeln!(out, "cur {}", self.cur)?;
+        writeln!(out, "relative_base {}", self.relative_base)?;
+        writeln!(out, "done {}", self.done as u8)?;
+        writeln!(out, "executed {}", self.executed)?;
+        let arithmetic = match self.arithmetic {
+            Arithmetic::Wrapping => "wrapping",
+            Arithmetic::Checked => "checked",
+        };
+        writeln!(out, "arithmetic {}", arithmetic)?;
+        if let Some(code) = self.exit_code {
+            writeln!(out, "exit_code {}", code)?;
+        }
+        if let Some(max) = self.budget.max_instructions {
+            writeln!(out, "max_instructions {}", max)?;
+        }
+        if let Some(timeout) = self.budget.timeout {
+            writeln!(out, "timeout_ns {}", timeout.as_nanos())?;
+        }
+        write!(out, "input")?;
+        for val in &self.input {
+            write!(out, " {}", val)?;
+        }
+        writeln!(out)?;
+        writeln!(out, "limit {}", self.memory.limit())?;
+        writeln!(out, "len {}", self.memory.len())?;
+
+        let mut run: Option<(usize, Vec<i64>)> = None;
+        for (addr, val) in self.memory.nonzero() {
+            match &mut run {
+                Some((start, vals)) if addr - (*start + vals.len()) < MAX_GAP => {
+                    vals.resize(addr - *start, 0);
+                    vals.push(val);
+                }
+                _ => {
+                    if let Some((start, vals)) = run.replace((addr, vec![val])) {
+                        write_run(out, start, &vals)?;
+                    }
+                }
+            }
+        }
+        if let Some((start, vals)) = run {
+            write_run(out, start, &vals)?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: BufRead>(input: R) -> io::Result<Self> {
+        let mut lines = input.lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let mut words = header.split_whitespace();
+        if words.next() != Some(MAGIC) {
+            return Err(invalid(1, "not an intcode snapshot"));
+        }
+        let version: u32 = parse(1, words.next())?;
+        if version == 0 || version > VERSION {
+            return Err(invalid(
+                1,
+                format!("unsupported snapshot version {}", version),
+            ));
+        }
+
+        let mut snapshot = Snapshot {
+            cur: 0,
+            relative_base: 0,
+            done: false,
+            executed: 0,
+            input: Vec::new(),
+            memory: Memory::new(Vec::new()),
+            arithmetic: Arithmetic::default(),
+            exit_code: None,
+            budget: Budget::default(),
+        };
+        let mut len = 0;
+        let mut cur_line = 0;
+        for (idx, line) in lines.enumerate() {
+            let line_no = idx + 2;
+            let line = line?;
+            let mut words = line.split_whitespace();
+            match words.next() {
+                None => {}
+                Some("cur") => {
+                    snapshot.cur = parse(line_no, words.next())?;
+                    cur_line = line_no;
+                }
+                Some("relative_base") => snapshot.relative_base = parse(line_no, words.next())?,
+                Some("done") => snapshot.done = parse::<u8>(line_no, words.next())? != 0,
+                Some("executed") => snapshot.executed = parse(line_no, words.next())?,
+                Some("arithmetic") => {
+                    snapshot.arithmetic = match words.next() {
+                        Some("wrapping") => Arithmetic::Wrapping,
+                        Some("checked") => Arithmetic::Checked,
+                        word => {
+                            return Err(invalid(
+                                line_no,
+                                format!("invalid arithmetic '{}'", word.unwrap_or_default()),
+                            ))
+                        }
+                    }
+                }
+                Some("exit_code") => snapshot.exit_code = Some(parse(line_no, words.next())?),
+                Some("max_instructions") => {
+                    snapshot.budget.max_instructions = Some(parse(line_no, words.next())?)
+                }
+                Some("timeout_ns") => {
+                    snapshot.budget.timeout =
+                        Some(Duration::from_nanos(parse(line_no, words.next())?))
+                }
+                Some("input") => {
+                    snapshot.input = words
+                        .map(|w| parse(line_no, Some(w)))
+                        .collect::<io::Result<_>>()?
+                }
+                Some("limit") => {
+                    let limit = parse(line_no, words.next())?;
+                    if limit > memory::MAX_LIMIT {
+                        return Err(invalid(
+                            line_no,
+                            format!("memory limit {} is too large", limit),
+                        ));
+                    }
+                    snapshot.memory.set_limit(limit);
+                }
+                Some("len") => {
+                    len = parse(line_no, words.next())?;
+                    if len > snapshot.memory.limit() {
+                        return Err(invalid(line_no, "length exceeds limit"));
+                    }
+                }
+                Some("mem") => {
+                    let start: usize = parse(line_no, words.next())?;
+                    let exceeds = || invalid(line_no, "memory exceeds limit");
+                    if start >= snapshot.memory.limit() {
+                        return Err(exceeds());
+                    }
+                    for (offset, word) in words.enumerate() {
+                        let addr = start.checked_add(offset).ok_or_else(exceeds)?;
+                        snapshot
+                            .memory
+                            .set(addr, parse(line_no, Some(word))?)
+                            .ok_or_else(exceeds)?;
+                    }
+                }
+                Some(key) => return Err(invalid(line_no, format!("unknown field '{}'", key))),
+            }
+        }
+        // The limit may come after `cur`, so it is only checked at the end.
+        if snapshot.cur >= snapshot.memory.limit() {
+            return Err(invalid(cur_line, "cur exceeds limit"));
+        }
+        snapshot.memory.grow_to(len);
+        Ok(snapshot)
+    }
+
+    pub fn diff(&self, other: &Snapshot) -> Vec<Change> {
+        let mut ret = Vec::new();
+        if self.cur != other.cur {
+            ret.push(Change::Cur(self.cur, other.cur));
+        }
+        if self.relative_base != other.relative_base {
+            ret.push(Change::RelativeBase(
+                self.relative_base,
+                other.relative_base,
+            ));
+        }
+        if self.done != other.done {
+            ret.push(Change::Done(self.done, other.done));
+        }
+        if self.memory.len() != other.memory.len() {
+            ret.push(Change::Len(self.memory.len(), other.memory.len()));
+        }
+        ret.extend(
+            self.memory
+                .diff(&other.memory)
+                .into_iter()
+                .map(|(addr, old, new)| Change::Memory { addr, old, new }),
+        );
+        ret
+    }
+}
+
+fn write_run<W: Write>(out: &mut W, start: usize, vals: &[i64]) -> io::Result<()> {
+    write!(out, "mem {}", start)?;
+    for val in vals {
+        write!(out, " {}", val)?;
+    }
+    writeln!(out)
+}
+
+impl Machine {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cur: self.cur,
+            relative_base: self.relative_base,
+            done: self.done,
+            executed: self.executed,
+            input: self.input_queue.iter().copied().collect(),
+            memory: self.code.clone(),
+            arithmetic: self.arithmetic,
+            exit_code: self.exit_code,
+            budget: Budget {
+                max_instructions: self
+                    .budget
+                    .max_instructions
+                    .map(|max| max.saturating_sub(self.executed.saturating_sub(self.budget_start))),
+                timeout: self.budget.timeout,
+            },
+        }
+    }
+
+    pub fn from_snapshot(snapshot: Snapshot) -> Self {
+        let mut machine = Machine::with_memory(snapshot.memory);
+        machine.cur = snapshot.cur;
+        machine.relative_base = snapshot.relative_base;
+        machine.done = snapshot.done;
+        machine.executed = snapshot.executed;
+        machine.input_queue = snapshot.input.into();
+        machine.arithmetic = snapshot.arithmetic;
+        machine.exit_code = snapshot.exit_code;
+        machine.set_budget(snapshot.budget);
+        machine
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.snapshot().save(path)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Snapshot::load(path).map(Machine::from_snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ErrorKind, Status};
+    use super::*;
+    use std::iter;
+
+    // Reads numbers into [21], adds them to a running total at [20] and echoes it.
+    const ACCUMULATOR: [i64; 22] = [
+        109, 3, 203, 18, 1, 20, 21, 20, 4, 20, 1105, 1, 2, 99, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    fn roundtrip(snapshot: &Snapshot) -> Snapshot {
+        let mut buf = Vec::new();
+        snapshot.write(&mut buf).unwrap();
+        Snapshot::read(&buf[..]).unwrap()
+    }
+
+    #[test]
+    fn snapshot_roundtrip_resumes() {
+        let mut machine = Machine::new(ACCUMULATOR.to_vec());
+        assert_eq!(machine.resume_with(5), Ok(Status::Output(5)));
+        machine.push_input(7);
+        let mut restored = Machine::from_snapshot(roundtrip(&machine.snapshot()));
+        assert_eq!(restored.cur(), machine.cur());
+        assert_eq!(restored.relative_base(), 3);
+        assert_eq!(restored.executed(), machine.executed());
+        assert_eq!(restored.resume(), Ok(Status::Output(12)));
+        assert_eq!(restored.resume_with(1), Ok(Status::Output(13)));
+    }
+
+    #[test]
+    fn snapshot_keeps_machine_settings() {
+        let mut machine = Machine::new(vec![1101, i64::MAX, 1, 0, 99]);
+        machine.set_arithmetic(Arithmetic::Checked);
+        machine.set_budget(Budget {
+            max_instructions: Some(10),
+            timeout: Some(Duration::from_millis(1500)),
+        });
+        machine.exit_code = Some(3);
+        let snapshot = roundtrip(&machine.snapshot());
+        assert_eq!(snapshot.arithmetic, Arithmetic::Checked);
+        assert_eq!(snapshot.exit_code, Some(3));
+        assert_eq!(snapshot.budget, machine.budget());
+
+        let mut restored = Machine::from_snapshot(snapshot);
+        assert_eq!(restored.exit_code(), Some(3));
+        assert_eq!(
+            restored.run_to_end(None).unwrap_err().kind,
+            super::super::ErrorKind::Overflow
+        );
+
+        // Version 1 files have none of these and get the defaults.
+        let old = Snapshot::read(&b"intcode-snapshot 1\ncur 0\nmem 0 99\n"[..]).unwrap();
+        assert_eq!(old.arithmetic, Arithmetic::Wrapping);
+        assert_eq!((old.exit_code, old.budget), (None, Budget::default()));
+    }
+
+    #[test]
+    fn snapshot_keeps_remaining_instructions() {
+        let mut machine = Machine::new(vec![104, 1, 1105, 1, 0]);
+        machine.set_budget(Budget::instructions(10));
+        assert_eq!(machine.resume(), Ok(Status::Output(1)));
+        assert_eq!(machine.resume(), Ok(Status::Output(1)));
+        let snapshot = roundtrip(&machine.snapshot());
+        assert_eq!(snapshot.budget, Budget::instructions(7));
+        assert_eq!(
+            Machine::from_snapshot(snapshot)
+                .run_to_end(None)
+                .unwrap_err()
+                .kind,
+            super::super::ErrorKind::InstructionLimit { executed: 7 }
+        );
+    }
+
+    #[test]
+    fn snapshot_format_is_sparse() {
+        let mut machine = Machine::with_initial_size(vec![1, 0, 0, 2], 4096);
+        machine.code.set(3000, -4);
+        let mut buf = Vec::new();
+        machine.snapshot().write(&mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.starts_with("intcode-snapshot 2\ncur 0\n"));
+        assert!(text.ends_with("len 4096\nmem 0 1 0 0 2\nmem 3000 -4\n"));
+
+        let restored = roundtrip(&machine.snapshot());
+        assert_eq!(restored.memory.len(), 4096);
+        assert_eq!(restored.memory.to_vec(), machine.memory().to_vec());
+    }
+
+    #[test]
+    fn snapshot_rejects_bad_input() {
+        let err = Snapshot::read(&b"intcode-snapshot 3\n"[..]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "snapshot line 1: unsupported snapshot version 3"
+        );
+        let err = Snapshot::read(&b"intcode-snapshot 1\ncur x\n"[..]).unwrap_err();
+        assert_eq!(err.to_string(), "snapshot line 2: invalid value 'x'");
+        assert!(Snapshot::read(&b"1,2,3\n"[..]).is_err());
+
+        let err =
+            Snapshot::read(&b"intcode-snapshot 1\nlimit 18446744073709551615\n"[..]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "snapshot line 2: memory limit 18446744073709551615 is too large"
+        );
+        for mem in &["mem 1000000000000 5", "mem 18446744073709551614 1 2 3"] {
+            let text = format!("intcode-snapshot 1\n{}\n", mem);
+            let err = Snapshot::read(text.as_bytes()).unwrap_err();
+            assert_eq!(err.to_string(), "snapshot line 2: memory exceeds limit");
+        }
+        let err = Snapshot::read(&b"intcode-snapshot 1\nlimit 16\nlen 17\n"[..]).unwrap_err();
+        assert_eq!(err.to_string(), "snapshot line 3: length exceeds limit");
+        let err =
+            Snapshot::read(&b"intcode-snapshot 1\ncur 999999999999\nlimit 16\n"[..]).unwrap_err();
+        assert_eq!(err.to_string(), "snapshot line 2: cur exceeds limit");
+    }
+
+    #[test]
+    fn snapshot_keeps_large_memory_limit() {
+        let mut machine = Machine::with_memory_limit(vec![1101, 7, 0, 1 << 30, 99], 1 << 31);
+        machine.run_to_end(iter::empty()).unwrap();
+        let mut text = Vec::new();
+        machine.snapshot().write(&mut text).unwrap();
+        let restored = Machine::from_snapshot(Snapshot::read(&text[..]).unwrap());
+        assert_eq!(restored.memory().limit(), 1 << 31);
+        assert_eq!(restored.memory()[1 << 30], 7);
+    }
+
+    #[test]
+    fn snapshot_cursor_past_memory_limit() {
+        let mut snapshot = Machine::new(vec![99]).snapshot();
+        snapshot.cur = 1 << 40;
+        let err = Machine::from_snapshot(snapshot)
+            .run_to_end(iter::empty())
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::OutOfBounds { addr: 1 << 40 });
+        assert_eq!(err.instruction, 0);
+    }
+    #[test]
+    fn snapshot_diff() {
+        let mut machine = Machine::new(ACCUMULATOR.to_vec());
+        machine.resume_with(5).unwrap();
+        let before = machine.snapshot();
+        machine.resume_with(2).unwrap();
+        assert_eq!(
+            before.diff(&machine.snapshot()),
+            &[
+                Change::Memory {
+                    addr: 20,
+                    old: 5,
+                    new: 7
+                },
+                Change::Memory {
+                    addr: 21,
+                    old: 5,
+                    new: 2
+                },
+            ]
+        );
+    }
+}
@@ -1,16 +1,22 @@
 #![cfg(test)]
 
+use super::fast::FastMachine;
+use super::fixtures::COUNTDOWN;
+use super::fuzz::{random_program, XorShift};
 use super::*;
 use std::iter;
+use std::panic::{self, AssertUnwindSafe};
 
 fn test_machine_states(input: &[i64], expected_output: &[i64]) {
     let mut machine = Machine::new(input.to_vec());
     machine.run_to_end(std::iter::empty()).unwrap();
-    assert_eq!(machine.code.as_slice(), expected_output);
+    assert_eq!(machine.code.to_vec(), expected_output);
+    assert_engines_agree(Machine::new(input.to_vec()), &[]);
 }
 
 fn test_machine_output(machine: &[i64], input: &[i64], expected_output: &[i64]) {
     let mut machine = Machine::new(machine.to_vec());
+    assert_engines_agree(machine.clone(), input);
     assert_eq!(
         machine
             .run_to_end(input.iter().copied())
@@ -20,6 +26,45 @@ fn test_machine_output(machine: &[i64], input: &[i64], expected_output: &[i64])
     );
 }
 
+// Runs the same machine on the plain and the pre-decoding interpreter and checks
+// that neither panics and that output, errors and final state all match.
+fn assert_engines_agree(machine: Machine, input: &[i64]) {
+    let program = machine.code.to_vec();
+    let mut slow = machine.clone();
+    let slow_ret = panic::catch_unwind(AssertUnwindSafe(|| slow.run_to_end(input.iter().copied())));
+    let mut fast = FastMachine::from(machine);
+    let fast_ret = panic::catch_unwind(AssertUnwindSafe(|| fast.run_to_end(input.iter().copied())));
+    match (slow_ret, fast_ret) {
+        (Ok(slow_ret), Ok(fast_ret)) => assert_eq!(slow_ret, fast_ret),
+        (slow_ret, fast_ret) => panic!(
+            "engine panicked (interpreter: {}, fast: {}) on {:?}",
+            if slow_ret.is_err() { "panicked" } else { "ok" },
+            if fast_ret.is_err() { "panicked" } else { "ok" },
+            program
+        ),
+    }
+    let fast = fast.into_machine();
+    assert_eq!(slow.code.to_vec(), fast.code.to_vec());
+    assert_eq!(
+        (slow.cur, slow.relative_base, slow.done, slow.executed),
+        (fast.cur, fast.relative_base, fast.done, fast.executed)
+    );
+}
+
+#[test]
+fn intcode_engines_agree_on_random_programs() {
+    let mut rng = XorShift::new(0x2545_f491_4f6c_dd1d);
+    for n in 0..2000 {
+        let mut machine = Machine::with_memory_limit(random_program(&mut rng, 40), 64);
+        machine.set_budget(Budget::instructions(200));
+        if n % 2 == 1 {
+            machine.set_arithmetic(Arithmetic::Checked);
+        }
+        let input: Vec<_> = (0..rng.below(4)).map(|_| rng.below(20) - 5).collect();
+        assert_engines_agree(machine, &input);
+    }
+}
+
 #[test]
 fn intcode_machine_add() {
     test_machine_states(&[1, 0, 0, 0, 99], &[2, 0, 0, 0, 99]);
@@ -131,7 +176,7 @@ fn intcode_machine_invalid_opcode() {
 #[test]
 fn intcode_machine_larger_size() {
     assert_eq!(
-        Machine::with_initial_size(vec![1, 2, 3], 5).code.as_slice(),
+        Machine::with_initial_size(vec![1, 2, 3], 5).code.to_vec(),
         &[1, 2, 3, 0, 0]
     );
 }
@@ -139,7 +184,165 @@ fn intcode_machine_larger_size() {
 #[test]
 fn intcode_machine_smaller_size() {
     assert_eq!(
-        Machine::with_initial_size(vec![1, 2, 3], 2).code.as_slice(),
+        Machine::with_initial_size(vec![1, 2, 3], 2).code.to_vec(),
         &[1, 2]
     );
 }
+
+#[test]
+fn intcode_machine_assembled_countdown() {
+    let code = asm::assemble(COUNTDOWN).unwrap();
+    test_machine_output(&code, &[], &[3, 2, 1]);
+}
+
+#[test]
+fn intcode_machine_resume_interleaved() {
+    let mut machine = Machine::new(vec![3, 11, 4, 11, 1001, 11, 1, 11, 4, 11, 99, 0]);
+    assert_eq!(machine.resume().unwrap(), Status::NeedsInput);
+    assert_eq!(machine.resume_with(41).unwrap(), Status::Output(41));
+    assert_eq!(machine.resume().unwrap(), Status::Output(42));
+    assert_eq!(machine.resume().unwrap(), Status::Halted);
+    assert_eq!(machine.resume().unwrap(), Status::Halted);
+}
+
+#[test]
+fn intcode_machine_resume_keeps_early_input() {
+    let mut machine = Machine::new(vec![104, 7, 3, 0, 4, 0, 99]);
+    assert_eq!(machine.resume_with(5).unwrap(), Status::Output(7));
+    assert_eq!(machine.resume().unwrap(), Status::Output(5));
+    assert!(machine.resume().unwrap() == Status::Halted && machine.done());
+}
+
+#[test]
+fn intcode_machine_run_to_end_after_pause() {
+    let mut machine = Machine::new(vec![3, 0, 4, 0, 99]);
+    assert_eq!(machine.resume().unwrap(), Status::NeedsInput);
+    assert_eq!(machine.run_to_end(iter::once(9)).unwrap(), &[9]);
+}
+
+#[test]
+fn intcode_machine_sparse_write() {
+    let mut machine = Machine::new(vec![21101, 6, 7, 1_000_000, 204, 1_000_000, 4, 9, 99, 0]);
+    assert_eq!(machine.run_to_end(iter::empty()).unwrap(), &[13, 0]);
+    assert_eq!(machine.memory().len(), 1_000_001);
+}
+
+#[test]
+fn intcode_machine_memory_limit() {
+    let mut machine = Machine::with_memory_limit(vec![1101, 1, 2, 100, 99], 64);
+    assert!(matches!(
+        machine.run_to_end(iter::empty()),
+        Err(Error {
+            kind: ErrorKind::OutOfBounds { addr: 100 },
+            ..
+        })
+    ));
+    machine.set_memory_limit(128);
+    assert!(machine.run_to_end(iter::empty()).is_ok());
+}
+
+#[test]
+fn intcode_machine_instruction_limit() {
+    let mut machine = Machine::new(vec![1101, 0, 0, 7, 1105, 1, 0, 0]);
+    machine.set_budget(Budget::instructions(101));
+    let err = machine.run_to_end(iter::empty()).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InstructionLimit { executed: 101 });
+    assert_eq!(err.ip, 4);
+    machine.set_budget(Budget::instructions(10));
+    assert_eq!(
+        machine.run_to_end(iter::empty()).unwrap_err().kind,
+        ErrorKind::InstructionLimit { executed: 10 }
+    );
+    assert_eq!(machine.executed(), 111);
+}
+
+#[test]
+fn intcode_machine_deadline() {
+    let mut machine = Machine::new(vec![1105, 1, 0]);
+    machine.set_budget(Budget::timeout(Duration::from_millis(10)));
+    match machine.run_to_end(iter::empty()).unwrap_err().kind {
+        ErrorKind::DeadlineExceeded { executed } => assert!(executed > 0),
+        kind => panic!("unexpected error {:?}", kind),
+    }
+}
+
+#[test]
+fn intcode_machine_deadline_starts_at_set_budget() {
+    let budget = Budget::timeout(Duration::from_millis(50));
+    std::thread::sleep(Duration::from_millis(60));
+    let mut machine = Machine::new(vec![1101, 1, 2, 0, 99]);
+    machine.set_budget(budget);
+    assert_eq!(machine.run_to_end(iter::empty()), Ok(vec![]));
+}
+
+#[test]
+fn intcode_machine_budget_allows_completion() {
+    let mut machine = Machine::new(vec![3, 0, 4, 0, 99]);
+    machine.set_budget(Budget::instructions(3));
+    assert_eq!(machine.run_to_end(iter::once(6)).unwrap(), &[6]);
+}
+
+// Squares the input four times: x^16 overflows an i64 for x = 20.
+const POWER_16: [i64; 22] = [
+    3, 21, 2, 21, 21, 21, 2, 21, 21, 21, 2, 21, 21, 21, 2, 21, 21, 21, 4, 21, 99, 0,
+];
+
+#[test]
+fn intcode_machine_wrapping_arithmetic() {
+    let program = [1101, i64::MAX, 1, 7, 4, 7, 99, 0];
+    let mut machine = Machine::new(program.to_vec());
+    assert_eq!(machine.arithmetic(), Arithmetic::Wrapping);
+    assert_eq!(machine.run_to_end(iter::empty()).unwrap(), &[i64::MIN]);
+    assert_engines_agree(Machine::new(program.to_vec()), &[]);
+}
+
+#[test]
+fn intcode_machine_checked_arithmetic() {
+    let mut machine = Machine::new(vec![1101, i64::MAX, 1, 7, 4, 7, 99, 0]);
+    machine.set_arithmetic(Arithmetic::Checked);
+    let err = machine.run_to_end(iter::empty()).unwrap_err();
+    assert_eq!(
+        (err.kind, err.ip, err.instruction),
+        (ErrorKind::Overflow, 0, 1101)
+    );
+
+    let mut machine = Machine::new(vec![109, i64::MAX, 109, 1, 99]);
+    machine.set_arithmetic(Arithmetic::Checked);
+    let err = machine.run_to_end(iter::empty()).unwrap_err();
+    assert_eq!((err.kind, err.ip), (ErrorKind::Overflow, 2));
+    assert_eq!(machine.relative_base(), i64::MAX);
+}
+
+#[test]
+fn intcode_machine_bigint_cells() {
+    use super::bigint::BigInt;
+
+    let code = POWER_16.iter().copied().map(BigInt::from).collect();
+    let mut machine = Machine::<BigInt>::from_cells(code);
+    machine.set_arithmetic(Arithmetic::Checked);
+    let output = machine.run_to_end(iter::once(BigInt::from(20))).unwrap();
+    assert_eq!(output.len(), 1);
+    assert_eq!(output[0].to_string(), "655360000000000000000");
+
+    let mut machine = Machine::new(POWER_16.to_vec());
+    machine.set_arithmetic(Arithmetic::Checked);
+    let err = machine.run_to_end(iter::once(20)).unwrap_err();
+    assert_eq!((err.kind, err.ip), (ErrorKind::Overflow, 14));
+}
+
+#[test]
+fn intcode_machine_bigint_unrepresentable_address() {
+    use super::bigint::BigInt;
+
+    let huge: BigInt = "100000000000000000000".parse().unwrap();
+    let code = vec![BigInt::from(4), huge, BigInt::from(99)];
+    let err = Machine::<BigInt>::from_cells(code)
+        .run_to_end(iter::empty())
+        .unwrap_err();
+    assert_eq!(
+        err.kind,
+        ErrorKind::Unrepresentable {
+            value: "100000000000000000000".to_string()
+        }
+    );
+}
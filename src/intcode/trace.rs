@@ -0,0 +1,469 @@
+use super::disasm::{Op, Param};
+use super::io::{IntcodeInput, IntcodeOutput};
+use super::{Error, ErrorKind, Machine, Status};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Write};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Effect {
+    None,
+    Write { addr: usize, value: i64 },
+    Output(i64),
+    RelativeBase(i64),
+    Halt,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TraceEvent {
+    pub addr: usize,
+    pub instruction: i64,
+    pub opcode: i64,
+    pub operands: Vec<i64>,
+    pub next: usize,
+    pub effect: Effect,
+}
+
+impl TraceEvent {
+    pub fn mnemonic(&self) -> &'static str {
+        Op::from_opcode(self.opcode).map_or("???", Op::mnemonic)
+    }
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>5}: {}", self.addr, self.mnemonic())?;
+        for (idx, val) in self.operands.iter().enumerate() {
+            write!(f, "{}{}", if idx == 0 { " " } else { ", " }, val)?;
+        }
+        match self.effect {
+            Effect::None => write!(f, " -> {}", self.next),
+            Effect::Write { addr, value } => write!(f, " => [{}] = {}", addr, value),
+            Effect::Output(val) => write!(f, " => out {}", val),
+            Effect::RelativeBase(rb) => write!(f, " => rb = {}", rb),
+            Effect::Halt => write!(f, " => halt"),
+        }
+    }
+}
+
+pub trait Tracer {
+    fn trace(&mut self, event: &TraceEvent);
+}
+
+impl<F: FnMut(&TraceEvent)> Tracer for F {
+    fn trace(&mut self, event: &TraceEvent) {
+        self(event)
+    }
+}
+
+impl Tracer for Vec<TraceEvent> {
+    fn trace(&mut self, event: &TraceEvent) {
+        self.push(event.clone());
+    }
+}
+
+impl Machine {
+    pub fn run_traced<I, O, T>(
+        &mut self,
+        input: &mut I,
+        output: &mut O,
+        tracer: &mut T,
+    ) -> Result<Status, Error>
+    where
+        I: IntcodeInput,
+        O: IntcodeOutput,
+        T: Tracer,
+    {
+        while !self.done {
+            match self.step_traced(input, output, tracer) {
+                Ok(()) => {}
+                Err(ErrorKind::Eof) => return Ok(Status::NeedsInput),
+                Err(kind) => return Err(self.locate(kind)),
+            }
+        }
+        Ok(Status::Halted)
+    }
+
+    fn step_traced<I, O, T>(
+        &mut self,
+        input: &mut I,
+        output: &mut O,
+        tracer: &mut T,
+    ) -> Result<(), ErrorKind>
+    where
+        I: IntcodeInput,
+        O: IntcodeOutput,
+        T: Tracer,
+    {
+        self.check_budget()?;
+        let addr = self.cur;
+        let instruction = self.instruction()?;
+        let opcode = instruction % 100;
+        let operands = match Op::from_opcode(opcode)
+            .map(|op| {
+                op.params()
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, param)| match param {
+                        Param::Val => self.get_val_arg(idx),
+                        Param::Addr => self.get_addr_arg(idx),
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()
+        {
+            Ok(operands) => operands.unwrap_or_default(),
+            // The step fails the same way, but only after its execute
+            // permission check, so that it reports the same error as an
+            // untraced run.
+            Err(_) => return self.step_inner(input, output),
+        };
+        let relative_base = self.relative_base;
+        let mut emitted = None;
+        self.step_inner(input, &mut |val| {
+            emitted = Some(val);
+            output.push_output(val);
+        })?;
+        let effect = match (self.last_write, emitted) {
+            (Some((addr, _)), _) => Effect::Write {
+                addr,
+                value: self.code[addr],
+            },
+            (_, Some(val)) => Effect::Output(val),
+            _ if self.done => Effect::Halt,
+            _ if self.relative_base != relative_base => Effect::RelativeBase(self.relative_base),
+            _ => Effect::None,
+        };
+        tracer.trace(&TraceEvent {
+            addr,
+            instruction,
+            opcode,
+            operands,
+            next: self.cur,
+            effect,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Span {
+    start: usize,
+    ts: u64,
+    len: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    executed: u64,
+    by_addr: HashMap<usize, u64>,
+    by_opcode: BTreeMap<i64, u64>,
+    back_jumps: HashMap<(usize, usize), u64>,
+    writes: HashMap<usize, u64>,
+    spans: Vec<Span>,
+    max_spans: usize,
+    open_span: Option<Span>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::with_max_spans(100_000)
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_spans(max_spans: usize) -> Self {
+        Profiler {
+            executed: 0,
+            by_addr: HashMap::new(),
+            by_opcode: BTreeMap::new(),
+            back_jumps: HashMap::new(),
+            writes: HashMap::new(),
+            spans: Vec::new(),
+            max_spans,
+            open_span: None,
+        }
+    }
+
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    pub fn addr_count(&self, addr: usize) -> u64 {
+        self.by_addr.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub fn opcode_counts(&self) -> &BTreeMap<i64, u64> {
+        &self.by_opcode
+    }
+
+    pub fn write_count(&self, addr: usize) -> u64 {
+        self.writes.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub fn hot_addrs(&self, count: usize) -> Vec<(usize, u64)> {
+        top(&self.by_addr, count)
+    }
+
+    // A jump backwards is the closing edge of a loop; the pair is reported as
+    // (loop head, loop end) along with how many iterations it took.
+    pub fn hot_loops(&self, count: usize) -> Vec<((usize, usize), u64)> {
+        top(&self.back_jumps, count)
+            .into_iter()
+            .map(|((from, to), n)| ((to, from), n))
+            .collect()
+    }
+
+    pub fn hot_writes(&self, count: usize) -> Vec<(usize, u64)> {
+        top(&self.writes, count)
+    }
+
+    pub fn write_report<W: Write>(&self, out: &mut W, count: usize) -> io::Result<()> {
+        writeln!(out, "executed {} instructions", self.executed)?;
+        writeln!(out, "\nby opcode:")?;
+        let mut opcodes: Vec<_> = self.by_opcode.iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (&opcode, &n) in opcodes {
+            let name = Op::from_opcode(opcode).map_or("???", Op::mnemonic);
+            writeln!(out, "  {:<4} {:>12} {:>6.2}%", name, n, self.percent(n))?;
+        }
+        writeln!(out, "\nhottest addresses:")?;
+        for (addr, n) in self.hot_addrs(count) {
+            writeln!(out, "  {:>6} {:>12} {:>6.2}%", addr, n, self.percent(n))?;
+        }
+        writeln!(out, "\nhottest loops:")?;
+        for ((head, end), n) in self.hot_loops(count) {
+            writeln!(out, "  {:>6}..={:<6} {:>12} iterations", head, end, n)?;
+        }
+        writeln!(out, "\nmemory write heatmap:")?;
+        self.write_heatmap(out)
+    }
+
+    // One row per 64-word block that saw any writes, one cell per 4 words,
+    // shaded by the log of the write count relative to the hottest cell.
+    fn write_heatmap<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        const SHADES: &[u8] = b" .:-=+*#%@";
+        let mut cells: BTreeMap<usize, [u64; 16]> = BTreeMap::new();
+        for (&addr, &n) in &self.writes {
+            cells.entry(addr / 64).or_insert([0; 16])[addr % 64 / 4] += n;
+        }
+        let max = cells.values().flatten().copied().max().unwrap_or(0);
+        for (row, counts) in cells {
+            let shades: String = counts
+                .iter()
+                .map(|&n| {
+                    let level = if n == 0 {
+                        0
+                    } else {
+                        1 + ((n as f64).ln() / (max as f64).ln().max(1.0)
+                            * (SHADES.len() - 2) as f64) as usize
+                    };
+                    SHADES[level.min(SHADES.len() - 1)] as char
+                })
+                .collect();
+            writeln!(out, "  {:>6} |{}|", row * 64, shades)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_chrome_trace<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{{\"traceEvents\":[")?;
+        let mut first = true;
+        let mut sep = |out: &mut W| -> io::Result<()> {
+            if !first {
+                writeln!(out, ",")?;
+            }
+            first = false;
+            Ok(())
+        };
+        for span in self.spans.iter().chain(&self.open_span) {
+            sep(out)?;
+            write!(
+                out,
+                "{{\"name\":\"block@{}\",\"cat\":\"intcode\",\"ph\":\"X\",\
+                 \"ts\":{},\"dur\":{},\"pid\":0,\"tid\":0,\"args\":{{\"addr\":{}}}}}",
+                span.start, span.ts, span.len, span.start
+            )?;
+        }
+        sep(out)?;
+        let counts: Vec<_> = self
+            .by_opcode
+            .iter()
+            .map(|(&op, n)| {
+                let name = Op::from_opcode(op).map_or("???", Op::mnemonic);
+                format!("\"{}\":{}", name, n)
+            })
+            .collect();
+        write!(
+            out,
+            "{{\"name\":\"opcodes\",\"ph\":\"C\",\"ts\":{},\"pid\":0,\"args\":{{{}}}}}",
+            self.executed,
+            counts.join(",")
+        )?;
+        writeln!(out, "\n],\"displayTimeUnit\":\"ns\"}}")
+    }
+
+    fn percent(&self, n: u64) -> f64 {
+        n as f64 * 100.0 / self.executed.max(1) as f64
+    }
+}
+
+impl Tracer for Profiler {
+    fn trace(&mut self, event: &TraceEvent) {
+        *self.by_addr.entry(event.addr).or_insert(0) += 1;
+        *self.by_opcode.entry(event.opcode).or_insert(0) += 1;
+        if let Effect::Write { addr, .. } = event.effect {
+            *self.writes.entry(addr).or_insert(0) += 1;
+        }
+        if event.next <= event.addr && event.effect != Effect::Halt {
+            *self.back_jumps.entry((event.addr, event.next)).or_insert(0) += 1;
+        }
+
+        let span = self.open_span.get_or_insert(Span {
+            start: event.addr,
+            ts: self.executed,
+            len: 0,
+        });
+        span.len += 1;
+        let sequential = event.next == event.addr + 1 + event.operands.len();
+        if !sequential || event.effect == Effect::Halt {
+            let span = self.open_span.take().unwrap();
+            if self.spans.len() < self.max_spans {
+                self.spans.push(span);
+            }
+        }
+        self.executed += 1;
+    }
+}
+
+fn top<K: Copy + Ord>(map: &HashMap<K, u64>, count: usize) -> Vec<(K, u64)> {
+    let mut ret: Vec<_> = map.iter().map(|(&k, &n)| (k, n)).collect();
+    ret.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    ret.truncate(count);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::super::fixtures::COUNTDOWN;
+    use super::super::protect::Permission;
+    use super::super::Budget;
+    use super::*;
+    use std::iter;
+
+    #[test]
+    fn trace_events() {
+        let mut machine = Machine::new(vec![1101, 2, 3, 9, 109, -4, 204, 13, 99, 0]);
+        let mut events = Vec::new();
+        let mut output = Vec::new();
+        machine
+            .run_traced(&mut iter::empty(), &mut output, &mut events)
+            .unwrap();
+        let text: Vec<_> = events.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            text,
+            &[
+                "    0: add 2, 3, 9 => [9] = 5",
+                "    4: mrb -4 => rb = -4",
+                "    6: out 5 => out 5",
+                "    8: end => halt",
+            ]
+        );
+        assert_eq!(output, &[5]);
+    }
+
+    #[test]
+    fn trace_errors_match_untraced_runs() {
+        let traced = |machine: &mut Machine| {
+            machine.run_traced(&mut iter::empty(), &mut Vec::new(), &mut Vec::new())
+        };
+        let untraced = |machine: &mut Machine| machine.run_io(&mut iter::empty(), &mut Vec::new());
+
+        // The budget runs out right before an instruction reading out of bounds.
+        let mut machine = Machine::new(vec![1101, 1, 1, 5, 4, -1]);
+        machine.set_budget(Budget::instructions(1));
+        let mut copy = machine.clone();
+        assert_eq!(
+            traced(&mut machine).unwrap_err().kind,
+            ErrorKind::InstructionLimit { executed: 1 }
+        );
+        assert_eq!(traced(&mut machine), untraced(&mut copy));
+
+        // Its operand cell may be read but not executed.
+        let mut machine = Machine::new(vec![4, 1, 99]);
+        machine.protect(1..2, Permission::ReadOnly);
+        let mut copy = machine.clone();
+        assert_eq!(traced(&mut machine), untraced(&mut copy));
+
+        let mut machine = Machine::new(assemble(COUNTDOWN).unwrap());
+        machine.protect(0..10, Permission::ExecuteOnly);
+        let mut copy = machine.clone();
+        traced(&mut machine).unwrap();
+        untraced(&mut copy).unwrap();
+        assert_eq!(
+            machine.protection().unwrap().executed().collect::<Vec<_>>(),
+            copy.protection().unwrap().executed().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn profiler_counts() {
+        let mut machine = Machine::new(assemble(COUNTDOWN).unwrap());
+        let mut profiler = Profiler::new();
+        machine
+            .run_traced(&mut iter::empty(), &mut Vec::new(), &mut profiler)
+            .unwrap();
+        assert_eq!(profiler.executed(), 10);
+        assert_eq!(profiler.addr_count(0), 3);
+        assert_eq!(profiler.opcode_counts()[&5], 3);
+        assert_eq!(profiler.hot_loops(1), &[((0, 6), 2)]);
+        assert_eq!(profiler.write_count(10), 3);
+        assert_eq!(
+            profiler.spans,
+            &[
+                Span {
+                    start: 0,
+                    ts: 0,
+                    len: 3
+                },
+                Span {
+                    start: 0,
+                    ts: 3,
+                    len: 3
+                },
+                Span {
+                    start: 0,
+                    ts: 6,
+                    len: 4
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn profiler_exports() {
+        let mut machine = Machine::new(assemble(COUNTDOWN).unwrap());
+        let mut profiler = Profiler::new();
+        machine
+            .run_traced(&mut iter::empty(), &mut Vec::new(), &mut profiler)
+            .unwrap();
+        let mut report = Vec::new();
+        profiler.write_report(&mut report, 3).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("executed 10 instructions\n"));
+        assert!(report.contains("       0..=6                 2 iterations"));
+        assert!(report.contains("       0 |  @             |"));
+
+        let mut json = Vec::new();
+        profiler.write_chrome_trace(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\"traceEvents\":["));
+        assert_eq!(json.matches("\"ph\":\"X\"").count(), 3);
+        assert!(json.contains("\"args\":{\"add\":3,\"out\":3,\"jt\":3,\"end\":1}"));
+    }
+}
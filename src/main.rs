@@ -4,17 +4,29 @@ mod algorithms;
 mod image;
 mod intcode;
 
+use std::env;
 use std::fs::File;
-use std::io::{self, prelude::*, BufReader};
+use std::io::{self, prelude::*};
+use std::thread;
 
-use image::Image;
-use intcode::Machine;
+use intcode::cfg::Cfg;
+use intcode::compile;
+use intcode::console::Console;
+use intcode::coverage::Coverage;
+use intcode::debugger::{self, Debugger};
+use intcode::disasm::disassemble;
+use intcode::fast::FastMachine;
+use intcode::fuzz;
+use intcode::io::{ReaderInput, WriterOutput};
+use intcode::loader;
+use intcode::search::{Search, Slot};
+use intcode::signature;
+use intcode::trace::Profiler;
+use intcode::{Machine, Status};
 
-fn parse_intcode<B: BufRead>(file: B) -> io::Result<Machine> {
-    file.split(b',')
-        .map(|x| Ok(String::from_utf8(x?).unwrap().parse().unwrap()))
-        .collect::<Result<_, _>>()
-        .map(|v| Machine::with_initial_size(v, 2048))
+fn load_machine(path: &str) -> io::Result<Machine> {
+    let code = loader::load_path(path)?;
+    Ok(Machine::new(code))
 }
 
 fn parse_orbits<B: BufRead>(file: B) -> io::Result<Vec<(String, String)>> {
@@ -28,16 +40,211 @@ fn parse_orbits<B: BufRead>(file: B) -> io::Result<Vec<(String, String)>> {
         .collect()
 }
 
+fn usage(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("usage: {}", msg))
+}
+
 fn main() -> io::Result<()> {
     //    let orbits = parse_orbits(BufReader::new(File::open("data/orbits.txt")?))?;
     //    let dist = algorithms::orbit_distance(&orbits, "YOU", "SAN");
     //    println!("{}", dist);
     //    Ok(())
 
-    let mut string = String::new();
-    let mut machine = parse_intcode(BufReader::new(File::open("data/boost.icm")?))?;
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("ascii") => {
+            let path = args.get(1).ok_or_else(|| usage("ascii <program>"))?;
+            let mut console = Console::new(load_machine(path)?);
+            let stdin = io::stdin();
+            console.interact(stdin.lock(), io::stdout())?;
+            Ok(())
+        }
+        Some("debug") => {
+            let path = args
+                .get(1)
+                .ok_or_else(|| usage("debug <program> [--history]"))?;
+            let mut debugger = Debugger::new(load_machine(path)?);
+            debugger.record_history(args.get(2).is_some_and(|arg| arg == "--history"));
+            let stdin = io::stdin();
+            debugger::repl(&mut debugger, stdin.lock(), io::stdout())
+        }
+        Some("profile") => {
+            let path = args
+                .get(1)
+                .ok_or_else(|| usage("profile <program> [--chrome <trace.json>] [input...]"))?;
+            let mut machine = load_machine(path)?;
+            let mut chrome = None;
+            let mut input = Vec::new();
+            let mut rest = args[2..].iter();
+            while let Some(arg) = rest.next() {
+                if arg == "--chrome" {
+                    chrome = rest.next();
+                } else {
+                    input.push(
+                        arg.parse()
+                            .map_err(|_| usage("input values must be integers"))?,
+                    );
+                }
+            }
+            let mut profiler = Profiler::new();
+            let mut output = Vec::new();
+            machine
+                .run_traced(&mut input.into_iter(), &mut output, &mut profiler)
+                .map_err(io::Error::other)?;
+            println!("output: {:?}\n", output);
+            profiler.write_report(&mut io::stdout(), 10)?;
+            if let Some(chrome) = chrome {
+                profiler.write_chrome_trace(&mut io::BufWriter::new(File::create(chrome)?))?;
+            }
+            Ok(())
+        }
+        Some("coverage") => {
+            let path = args
+                .get(1)
+                .ok_or_else(|| usage("coverage <program> [input...] [-- input...]..."))?;
+            let machine = load_machine(path)?;
+            let runs = args[2..]
+                .split(|arg| arg == "--")
+                .map(|run| run.iter().map(|arg| arg.parse()).collect())
+                .collect::<Result<Vec<Vec<i64>>, _>>()
+                .map_err(|_| usage("input values must be integers"))?;
+            let mut coverage = Coverage::new();
+            let mut failed = None;
+            for (idx, input) in runs.into_iter().enumerate() {
+                match machine.clone().run_traced(
+                    &mut input.into_iter(),
+                    &mut Vec::new(),
+                    &mut coverage,
+                ) {
+                    Ok(Status::NeedsInput) => {
+                        eprintln!("run {} stopped waiting for input", idx + 1)
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("run {} failed: {}", idx + 1, e);
+                        failed = Some(idx + 1);
+                        break;
+                    }
+                }
+            }
+            coverage.write_report(&machine.memory().to_vec(), &mut io::stdout().lock())?;
+            match failed {
+                Some(run) => Err(io::Error::other(format!("run {} failed", run))),
+                None => Ok(()),
+            }
+        }
+        Some("disasm") => {
+            let program = args.get(1).ok_or_else(|| usage("disasm <program>"))?;
+            let code = loader::load_path(program)?;
+            let mut listing = disassemble(&code);
+            signature::label(&mut listing, &signature::infer(&Cfg::new(&code)));
+            print!("{}", listing);
+            Ok(())
+        }
+        Some("cfg") => {
+            let program = args.get(1).ok_or_else(|| usage("cfg <program>"))?;
+            let cfg = Cfg::new(&loader::load_path(program)?);
+            cfg.write_dot(&mut io::stdout().lock())
+        }
+        Some("compile") => {
+            let program = args
+                .get(1)
+                .ok_or_else(|| usage("compile <program> [output.rs]"))?;
+            let source = compile::compile(&loader::load_path(program)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            match args.get(2) {
+                Some(path) => File::create(path)?.write_all(source.as_bytes()),
+                None => io::stdout().write_all(source.as_bytes()),
+            }
+        }
+        Some("pack") => {
+            let (program, packed) = match (args.get(1), args.get(2)) {
+                (Some(program), Some(packed)) => (program, packed),
+                _ => return Err(usage("pack <program> <output>")),
+            };
+            let code = loader::load_path(program)?;
+            let mut out = io::BufWriter::new(File::create(packed)?);
+            loader::write_binary(&code, &mut out)?;
+            out.flush()
+        }
+        Some("save") => {
+            let (program, snapshot) = match (args.get(1), args.get(2)) {
+                (Some(program), Some(snapshot)) => (program, snapshot),
+                _ => return Err(usage("save <program> <snapshot> [input...]")),
+            };
+            let mut machine = load_machine(program)?;
+            let input = args[3..]
+                .iter()
+                .map(|arg| arg.parse())
+                .collect::<Result<Vec<i64>, _>>()
+                .map_err(|_| usage("input values must be integers"))?;
+            let mut output = Vec::new();
+            machine
+                .run_with(input, &mut output)
+                .map_err(io::Error::other)?;
+            println!("{:?}", output);
+            machine.save(snapshot)
+        }
+        Some("resume") => {
+            let path = args.get(1).ok_or_else(|| usage("resume <snapshot>"))?;
+            let mut machine = Machine::load(path)?;
+            let stdin = io::stdin();
+            let mut input = ReaderInput::new(stdin.lock());
+            let mut output = WriterOutput::new(io::stdout());
+            let status = machine
+                .run_io(&mut input, &mut output)
+                .map_err(io::Error::other)?;
+            output.finish()?;
+            if status == Status::NeedsInput {
+                machine.save(path)?;
+                eprintln!("waiting for input, saved to {}", path);
+            }
+            input.finish().map(|_| ())
+        }
+        Some("fuzz") => {
+            let mut config = fuzz::Config::default();
+            let parse = |arg: &String| arg.parse().map_err(|_| usage("fuzz [iterations] [seed]"));
+            if let Some(iterations) = args.get(1) {
+                config.iterations = parse(iterations)? as usize;
+            }
+            if let Some(seed) = args.get(2) {
+                config.seed = parse(seed)?;
+            }
+            let checked =
+                fuzz::run(&config).map_err(|failure| io::Error::other(failure.to_string()))?;
+            println!("{} programs ok", checked);
+            Ok(())
+        }
+        Some("search") => {
+            let (program, target) = match (args.get(1), args.get(2)) {
+                (Some(program), Some(target)) => (program, target),
+                _ => return Err(usage("search <program> <target>")),
+            };
+            let target: i64 = target
+                .parse()
+                .map_err(|_| usage("target must be an integer"))?;
+            let mut search = Search::new(load_machine(program)?);
+            search
+                .vary(Slot::Memory(1), 0..100)
+                .vary(Slot::Memory(2), 0..100)
+                .threads(thread::available_parallelism().map_or(1, |n| n.get()));
+            match search.find(|outcome| outcome.memory()[0] == target) {
+                Some(found) => {
+                    let noun = found.patch.get(Slot::Memory(1)).unwrap_or_default();
+                    let verb = found.patch.get(Slot::Memory(2)).unwrap_or_default();
+                    println!("noun {}, verb {}: {}", noun, verb, 100 * noun + verb);
+                }
+                None => println!("no noun and verb produce {}", target),
+            }
+            Ok(())
+        }
+        _ => {
+            let mut machine = FastMachine::from(load_machine("data/boost.icm")?);
 
-    println!("{:?}", machine.run_to_end(vec![2]).unwrap());
+            let output = machine.run_to_end(vec![2]).map_err(io::Error::other)?;
+            println!("{:?}", output);
 
-    Ok(())
+            Ok(())
+        }
+    }
 }